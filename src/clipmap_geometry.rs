@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+
+use crate::types::Vector3Hash;
+
+// Order of the meshes returned by GeoClipMap::generate
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MeshType {
+    Tile,
+    Filler,
+    Trim,
+    Cross,
+    Seam,
+    TileInner,
+    FillerInner,
+    TrimInner,
+}
+
+impl MeshType {
+    pub const ALL: [MeshType; 8] = [
+        MeshType::Tile,
+        MeshType::Filler,
+        MeshType::Trim,
+        MeshType::Cross,
+        MeshType::Seam,
+        MeshType::TileInner,
+        MeshType::FillerInner,
+        MeshType::TrimInner,
+    ];
+}
+
+// Samples terrain height at world XZ coordinates
+pub trait HeightSource {
+    fn get_height(&self, x: f32, z: f32) -> f32;
+}
+
+impl<F: Fn(f32, f32) -> f32> HeightSource for F {
+    fn get_height(&self, x: f32, z: f32) -> f32 {
+        self(x, z)
+    }
+}
+
+// Per-vertex NORMAL and TANGENT data. Tangents are xyz plus the binormal sign in w
+#[derive(Clone, Debug)]
+pub struct VertexShading {
+    pub normals: Vec<[f32; 3]>,
+    pub tangents: Vec<[f32; 4]>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClipmapAabb {
+    pub position: [f32; 3],
+    pub size: [f32; 3],
+}
+
+impl ClipmapAabb {
+    // Flat meshes still get a little height so they are never culled as degenerate
+    const MIN_HEIGHT: f32 = 0.1;
+
+    pub fn from_points(points: &[[f32; 3]]) -> Self {
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for point in points {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(point[axis]);
+                max[axis] = max[axis].max(point[axis]);
+            }
+        }
+        if points.is_empty() {
+            min = [0.0; 3];
+            max = [0.0; 3];
+        }
+
+        let mut size = [max[0] - min[0], max[1] - min[1], max[2] - min[2]];
+        size[1] = size[1].max(Self::MIN_HEIGHT);
+        Self { position: min, size }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClipmapMesh {
+    pub vertices: Vec<[f32; 3]>,
+    pub indices: Vec<u32>,
+    pub aabb: ClipmapAabb,
+}
+
+impl ClipmapMesh {
+    pub fn new(vertices: Vec<[f32; 3]>, indices: Vec<u32>) -> Self {
+        let aabb = ClipmapAabb::from_points(&vertices);
+        Self {
+            vertices,
+            indices,
+            aabb,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // Straight up normals with tangents along +X, matching UVs taken from XZ
+    pub fn flat_shading(&self) -> VertexShading {
+        VertexShading {
+            normals: vec![[0.0, 1.0, 0.0]; self.vertices.len()],
+            tangents: vec![[1.0, 0.0, 0.0, -1.0]; self.vertices.len()],
+        }
+    }
+
+    // Central differences over the height source, one grid unit either side of each vertex.
+    // Heights are sampled where an instance would put the mesh, with vertices spacing apart
+    // in XZ from origin
+    pub fn shading_from_heights(&self, source: &dyn HeightSource, spacing: f32, origin: [f32; 2]) -> VertexShading {
+        let mut normals = Vec::with_capacity(self.vertices.len());
+        let mut tangents = Vec::with_capacity(self.vertices.len());
+
+        for vertex in &self.vertices {
+            let (x, z) = (vertex[0] * spacing + origin[0], vertex[2] * spacing + origin[1]);
+            let dx = source.get_height(x + spacing, z) - source.get_height(x - spacing, z);
+            let dz = source.get_height(x, z + spacing) - source.get_height(x, z - spacing);
+
+            let normal = normalize([-dx, 2.0 * spacing, -dz]);
+            let tangent = normalize([2.0 * spacing, dx, 0.0]);
+            // Gram-Schmidt so the tangent stays perpendicular to the normal
+            let tangent = normalize(sub(tangent, scale(normal, dot(normal, tangent))));
+
+            normals.push(normal);
+            tangents.push([tangent[0], tangent[1], tangent[2], -1.0]);
+        }
+
+        VertexShading { normals, tangents }
+    }
+
+    // Geomorph factors along X and Z for CUSTOM0, from 0 inside the ring to 1 at its outer
+    // border where vertices must sit on the next level's lattice. center is the ring center
+    // in this mesh's local space
+    pub fn morph_factors(&self, center: [f32; 2], half_extent: f32) -> Vec<[f32; 2]> {
+        let ramp = |offset: f32| {
+            let t = ((offset.abs() / half_extent - MORPH_START) / (MORPH_END - MORPH_START)).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        };
+        self.vertices
+            .iter()
+            .map(|vertex| [ramp(vertex[0] - center[0]), ramp(vertex[2] - center[1])])
+            .collect()
+    }
+
+    // Hangs a vertical wall of the given depth off every open edge, hiding cracks against
+    // neighbouring rings without any shader support
+    pub fn with_skirt(&self, depth: f32) -> Self {
+        let mut edge_count: HashMap<(u32, u32), u32> = HashMap::new();
+        for tri in self.indices.chunks_exact(3) {
+            for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+                *edge_count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        let mut vertices = self.vertices.clone();
+        let mut indices = self.indices.clone();
+        let mut skirt_vertices = HashMap::new();
+        let mut skirt_vertex = |id: u32, vertices: &mut Vec<[f32; 3]>| -> u32 {
+            *skirt_vertices.entry(id).or_insert_with(|| {
+                let [x, y, z] = vertices[id as usize];
+                vertices.push([x, y - depth, z]);
+                (vertices.len() - 1) as u32
+            })
+        };
+
+        for tri in self.indices.chunks_exact(3) {
+            for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+                // Degenerate triangles, like the seam's, have no real border
+                if a == b || edge_count[&(a.min(b), a.max(b))] != 1 {
+                    continue;
+                }
+                let a_low = skirt_vertex(a, &mut vertices);
+                let b_low = skirt_vertex(b, &mut vertices);
+                // Reversed from the top edge so the wall faces away from the piece
+                indices.extend_from_slice(&[b, a, a_low, b, a_low, b_low]);
+            }
+        }
+
+        Self::new(vertices, indices)
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = [[f32; 3]; 3]> + '_ {
+        self.indices.chunks_exact(3).map(|tri| {
+            [
+                self.vertices[tri[0] as usize],
+                self.vertices[tri[1] as usize],
+                self.vertices[tri[2] as usize],
+            ]
+        })
+    }
+
+    // Splits every triangle in two along its longest edge, sharing the new midpoints
+    pub fn subdivided(&self) -> Self {
+        let mut vertices = Vec::with_capacity(self.vertices.len() * 2);
+        let mut indices = Vec::with_capacity(self.indices.len() * 2);
+        let mut vertex_map = HashMap::new();
+
+        let mut find_or_add_vertex = |vertex: [f32; 3]| -> u32 {
+            *vertex_map
+                .entry(Vector3Hash::from_array(vertex))
+                .or_insert_with(|| {
+                    vertices.push(vertex);
+                    (vertices.len() - 1) as u32
+                })
+        };
+
+        for [a, b, c] in self.triangles() {
+            let length_ab = length_squared(sub(b, a));
+            let length_bc = length_squared(sub(c, b));
+            let length_ca = length_squared(sub(a, c));
+
+            // Rotate so the longest edge is always p0 -> p1, keeping the winding
+            let (p0, p1, p2) = if length_ab >= length_bc && length_ab >= length_ca {
+                (a, b, c)
+            } else if length_bc >= length_ab && length_bc >= length_ca {
+                (b, c, a)
+            } else {
+                (c, a, b)
+            };
+
+            let id_0 = find_or_add_vertex(p0);
+            let id_1 = find_or_add_vertex(p1);
+            let id_2 = find_or_add_vertex(p2);
+            let mid_id = find_or_add_vertex(midpoint(p0, p1));
+
+            indices.extend_from_slice(&[id_0, mid_id, id_2, mid_id, id_1, id_2]);
+        }
+
+        Self {
+            vertices,
+            indices,
+            aabb: self.aabb,
+        }
+    }
+}
+
+pub struct ClipmapGeometry {
+    pub size: i32,
+    pub tile: ClipmapMesh,
+    pub filler: ClipmapMesh,
+    pub trim: ClipmapMesh,
+    pub cross: ClipmapMesh,
+    pub seam: ClipmapMesh,
+    pub tile_inner: ClipmapMesh,
+    pub filler_inner: ClipmapMesh,
+    pub trim_inner: ClipmapMesh,
+}
+
+impl ClipmapGeometry {
+    pub fn new(size: i32) -> Self {
+        let tile_resolution = size;
+        let patch_vert_resolution = tile_resolution + 1;
+        let clipmap_resolution = tile_resolution * 4 + 1;
+        let clipmap_vert_resolution = clipmap_resolution + 1;
+
+        let tile_inner = Self::build_tile(tile_resolution, patch_vert_resolution);
+        let filler_inner = Self::build_filler(tile_resolution, patch_vert_resolution);
+        let trim_inner = Self::build_trim(clipmap_resolution, clipmap_vert_resolution);
+        let cross = Self::build_cross(tile_resolution, patch_vert_resolution);
+        let seam = Self::build_seam(clipmap_vert_resolution);
+
+        Self {
+            size,
+            tile: tile_inner.subdivided(),
+            filler: filler_inner.subdivided(),
+            trim: trim_inner.subdivided(),
+            cross,
+            seam,
+            tile_inner,
+            filler_inner,
+            trim_inner,
+        }
+    }
+
+    pub fn get(&self, mesh_type: MeshType) -> &ClipmapMesh {
+        match mesh_type {
+            MeshType::Tile => &self.tile,
+            MeshType::Filler => &self.filler,
+            MeshType::Trim => &self.trim,
+            MeshType::Cross => &self.cross,
+            MeshType::Seam => &self.seam,
+            MeshType::TileInner => &self.tile_inner,
+            MeshType::FillerInner => &self.filler_inner,
+            MeshType::TrimInner => &self.trim_inner,
+        }
+    }
+
+    // Half the width of a level's ring in its own units. The ring is centered half a unit past
+    // the level's snapped position
+    pub fn ring_half_extent(&self) -> f32 {
+        (self.size * 2) as f32 + 0.5
+    }
+
+    // Ring center in the local space of a piece, placed the way FastTerrain::snap places it.
+    // tile is the grid position of a tile, from 0 to 3 on each axis, and ignored otherwise
+    pub fn ring_center(&self, mesh_type: MeshType, tile: [i32; 2]) -> [f32; 2] {
+        let size = self.size as f32;
+        match mesh_type {
+            MeshType::Tile | MeshType::TileInner => tile.map(|i| {
+                let fill = if i >= 2 { 1.0 } else { 0.0 };
+                (2 - i) as f32 * size + 0.5 - fill
+            }),
+            MeshType::Filler | MeshType::FillerInner | MeshType::Cross => [0.5, 0.5],
+            // Trims are placed at the ring center and rotated around it
+            MeshType::Trim | MeshType::TrimInner => [0.0, 0.0],
+            // The seam lies on the outer border wherever the camera is, so its own center will do
+            MeshType::Seam => [size * 2.0 + 1.0, size * 2.0 + 1.0],
+        }
+    }
+
+    fn patch_2d(x: i32, y: i32, resolution: i32) -> u32 {
+        (y * resolution + x) as u32
+    }
+
+    fn build_tile(tile_resolution: i32, patch_vert_resolution: i32) -> ClipmapMesh {
+        let mut vertices = Vec::with_capacity((patch_vert_resolution * patch_vert_resolution) as usize);
+        let mut indices = Vec::with_capacity((tile_resolution * tile_resolution * 6) as usize);
+
+        for y in 0..patch_vert_resolution {
+            for x in 0..patch_vert_resolution {
+                vertices.push([x as f32, 0.0, y as f32]);
+            }
+        }
+
+        for y in 0..tile_resolution {
+            for x in 0..tile_resolution {
+                indices.extend_from_slice(&[
+                    Self::patch_2d(x, y, patch_vert_resolution),
+                    Self::patch_2d(x + 1, y + 1, patch_vert_resolution),
+                    Self::patch_2d(x, y + 1, patch_vert_resolution),
+                    Self::patch_2d(x, y, patch_vert_resolution),
+                    Self::patch_2d(x + 1, y, patch_vert_resolution),
+                    Self::patch_2d(x + 1, y + 1, patch_vert_resolution),
+                ]);
+            }
+        }
+
+        ClipmapMesh::new(vertices, indices)
+    }
+
+    fn build_filler(tile_resolution: i32, patch_vert_resolution: i32) -> ClipmapMesh {
+        let mut vertices = Vec::with_capacity((patch_vert_resolution * 8) as usize);
+        let mut indices = Vec::with_capacity((tile_resolution * 24) as usize);
+        let offset = tile_resolution;
+
+        for i in 0..patch_vert_resolution {
+            vertices.push([(offset + i + 1) as f32, 0.0, 0.0]);
+            vertices.push([(offset + i + 1) as f32, 0.0, 1.0]);
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices.push([1.0, 0.0, (offset + i + 1) as f32]);
+            vertices.push([0.0, 0.0, (offset + i + 1) as f32]);
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices.push([-(offset + i) as f32, 0.0, 1.0]);
+            vertices.push([-(offset + i) as f32, 0.0, 0.0]);
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices.push([0.0, 0.0, -(offset + i) as f32]);
+            vertices.push([1.0, 0.0, -(offset + i) as f32]);
+        }
+
+        for i in 0..(tile_resolution * 4) {
+            let arm = i / tile_resolution;
+
+            let bl = ((arm + i) * 2) as u32;
+            let br = ((arm + i) * 2 + 1) as u32;
+            let tl = ((arm + i) * 2 + 2) as u32;
+            let tr = ((arm + i) * 2 + 3) as u32;
+
+            if arm % 2 == 0 {
+                indices.extend_from_slice(&[br, bl, tr, bl, tl, tr]);
+            } else {
+                indices.extend_from_slice(&[br, bl, tl, br, tl, tr]);
+            }
+        }
+
+        ClipmapMesh::new(vertices, indices)
+    }
+
+    fn build_trim(clipmap_resolution: i32, clipmap_vert_resolution: i32) -> ClipmapMesh {
+        let mut vertices = Vec::with_capacity((clipmap_vert_resolution * 4 + 2) as usize);
+        let mut indices = Vec::with_capacity(((clipmap_resolution * 2 + 1) * 6) as usize);
+
+        // L shape centered on the origin, covering the -X and -Z edges of the ring. The long
+        // arm runs the whole coarser level's hole, one quad more than the finer level
+        let offset = 0.5 * (clipmap_resolution + 1) as f32 + 0.5;
+
+        for i in 0..=clipmap_vert_resolution {
+            vertices.push([-offset, 0.0, (clipmap_vert_resolution - i) as f32 - offset]);
+            vertices.push([1.0 - offset, 0.0, (clipmap_vert_resolution - i) as f32 - offset]);
+        }
+
+        let start_of_horizontal = vertices.len() as u32;
+        for i in 0..clipmap_vert_resolution {
+            vertices.push([(i + 1) as f32 - offset, 0.0, -offset]);
+            vertices.push([(i + 1) as f32 - offset, 0.0, 1.0 - offset]);
+        }
+
+        for i in 0..clipmap_vert_resolution as u32 {
+            indices.extend_from_slice(&[
+                i * 2 + 1,
+                i * 2,
+                (i + 1) * 2,
+                (i + 1) * 2 + 1,
+                i * 2 + 1,
+                (i + 1) * 2,
+            ]);
+        }
+
+        for i in 0..clipmap_resolution as u32 {
+            indices.extend_from_slice(&[
+                start_of_horizontal + i * 2 + 1,
+                start_of_horizontal + i * 2,
+                start_of_horizontal + (i + 1) * 2,
+                start_of_horizontal + (i + 1) * 2 + 1,
+                start_of_horizontal + i * 2 + 1,
+                start_of_horizontal + (i + 1) * 2,
+            ]);
+        }
+
+        ClipmapMesh::new(vertices, indices)
+    }
+
+    fn build_cross(tile_resolution: i32, patch_vert_resolution: i32) -> ClipmapMesh {
+        let mut vertices = Vec::with_capacity((patch_vert_resolution * 8) as usize);
+        let mut indices = Vec::with_capacity((tile_resolution * 24 + 6) as usize);
+
+        for i in 0..(patch_vert_resolution * 2) {
+            vertices.push([(i - tile_resolution) as f32, 0.0, 0.0]);
+            vertices.push([(i - tile_resolution) as f32, 0.0, 1.0]);
+        }
+
+        let start_of_vertical = vertices.len() as u32;
+        for i in 0..(patch_vert_resolution * 2) {
+            vertices.push([0.0, 0.0, (i - tile_resolution) as f32]);
+            vertices.push([1.0, 0.0, (i - tile_resolution) as f32]);
+        }
+
+        for i in 0..(tile_resolution * 2 + 1) as u32 {
+            let bl = i * 2;
+            let br = i * 2 + 1;
+            let tl = i * 2 + 2;
+            let tr = i * 2 + 3;
+            indices.extend_from_slice(&[br, bl, tr, bl, tl, tr]);
+        }
+
+        for i in 0..(tile_resolution * 2 + 1) as u32 {
+            // The horizontal strip already covers the center quad
+            if i == tile_resolution as u32 {
+                continue;
+            }
+
+            let bl = start_of_vertical + i * 2;
+            let br = start_of_vertical + i * 2 + 1;
+            let tl = start_of_vertical + i * 2 + 2;
+            let tr = start_of_vertical + i * 2 + 3;
+            indices.extend_from_slice(&[br, tr, bl, bl, tr, tl]);
+        }
+
+        ClipmapMesh::new(vertices, indices)
+    }
+
+    fn build_seam(clipmap_vert_resolution: i32) -> ClipmapMesh {
+        let edge = clipmap_vert_resolution as usize;
+        let mut vertices = vec![[0.0; 3]; edge * 4];
+        let mut indices = Vec::with_capacity(edge * 6);
+
+        for i in 0..edge {
+            let fi = i as f32;
+            let fv = clipmap_vert_resolution as f32;
+            vertices[i] = [fi, 0.0, 0.0];
+            vertices[edge + i] = [fv, 0.0, fi];
+            vertices[edge * 2 + i] = [fv - fi, 0.0, fv];
+            vertices[edge * 3 + i] = [0.0, 0.0, fv - fi];
+        }
+
+        for i in (0..(edge * 4) as u32).step_by(2) {
+            indices.extend_from_slice(&[i + 1, i, i + 2]);
+        }
+
+        // Make the last triangle wrap around to the first vertex
+        if let Some(last) = indices.last_mut() {
+            *last = 0;
+        }
+
+        ClipmapMesh::new(vertices, indices)
+    }
+}
+
+// Part of a ring's half extent over which vertices morph onto the next level's lattice
+const MORPH_START: f32 = 0.75;
+const MORPH_END: f32 = 0.95;
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn scale(v: [f32; 3], s: f32) -> [f32; 3] {
+    [v[0] * s, v[1] * s, v[2] * s]
+}
+
+fn length_squared(v: [f32; 3]) -> f32 {
+    dot(v, v)
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = length_squared(v).sqrt();
+    if length > 0.0 {
+        scale(v, 1.0 / length)
+    } else {
+        v
+    }
+}
+
+fn midpoint(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0, (a[2] + b[2]) / 2.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const SIZE: i32 = 8;
+
+    type Point = (i32, i32);
+
+    // Places a piece the way FastTerrain::snap does, returning triangles in world XZ, doubled
+    // so the half units from subdivision and the trim offset stay exact
+    fn place(mesh: &ClipmapMesh, scale: f32, angle: f32, origin: [f32; 2]) -> Vec<[Point; 3]> {
+        let point = |v: [f32; 3]| -> Point {
+            let [x, z] = place_vertex(v, scale, angle, origin);
+            ((x * 2.0).round() as i32, (z * 2.0).round() as i32)
+        };
+        mesh.triangles().map(|[a, b, c]| [point(a), point(b), point(c)]).collect()
+    }
+
+    fn place_vertex(v: [f32; 3], scale: f32, angle: f32, origin: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = (-angle.to_radians()).sin_cos();
+        [
+            (v[0] * cos + v[2] * sin) * scale + origin[0],
+            (-v[0] * sin + v[2] * cos) * scale + origin[1],
+        ]
+    }
+
+    // Twice the signed area seen from +Y. Negative is clockwise, Godot's front face
+    fn winding(tri: &[Point; 3]) -> i64 {
+        let ab = ((tri[1].0 - tri[0].0) as i64, (tri[1].1 - tri[0].1) as i64);
+        let ac = ((tri[2].0 - tri[0].0) as i64, (tri[2].1 - tri[0].1) as i64);
+        ab.1 * ac.0 - ab.0 * ac.1
+    }
+
+    // All pieces of every level for a camera at the given XZ position, plus the bounds of the
+    // outermost level
+    fn assemble(geometry: &ClipmapGeometry, levels: i32, position: [f32; 2]) -> (Vec<[Point; 3]>, [f32; 4]) {
+        let floor = |value: f32, scale: f32| (value / scale).floor() * scale;
+        let mut triangles = place(&geometry.cross, 1.0, 0.0, [floor(position[0], 1.0), floor(position[1], 1.0)]);
+        let mut bounds = [0.0; 4];
+
+        for lod in 0..levels {
+            let scale = (1 << lod) as f32;
+            let snapped = [floor(position[0], scale), floor(position[1], scale)];
+            let tile_size = (SIZE << lod) as f32;
+            let base = [
+                snapped[0] - (SIZE << (lod + 1)) as f32,
+                snapped[1] - (SIZE << (lod + 1)) as f32,
+            ];
+
+            for x in 0..4 {
+                for y in 0..4 {
+                    if lod != 0 && (x == 1 || x == 2) && (y == 1 || y == 2) {
+                        continue;
+                    }
+                    let fill = |i: i32| if i >= 2 { scale } else { 0.0 };
+                    let origin = [
+                        base[0] + x as f32 * tile_size + fill(x),
+                        base[1] + y as f32 * tile_size + fill(y),
+                    ];
+                    triangles.extend(place(&geometry.tile, scale, 0.0, origin));
+                }
+            }
+            triangles.extend(place(&geometry.filler, scale, 0.0, snapped));
+            bounds = [base[0], base[1], base[0] + tile_size * 4.0 + scale, base[1] + tile_size * 4.0 + scale];
+
+            if lod != levels - 1 {
+                let next_scale = scale * 2.0;
+                let next_snapped = [floor(position[0], next_scale), floor(position[1], next_scale)];
+                let d = [position[0] - next_snapped[0], position[1] - next_snapped[1]];
+                let r = if d[0] >= scale { 0 } else { 2 } | if d[1] >= scale { 0 } else { 1 };
+                let angle = [0.0, 270.0, 90.0, 180.0][r];
+                let tile_center = [snapped[0] + scale * 0.5, snapped[1] + scale * 0.5];
+                triangles.extend(place(&geometry.trim, scale, angle, tile_center));
+
+                let next_base = [
+                    next_snapped[0] - (SIZE << (lod + 1)) as f32,
+                    next_snapped[1] - (SIZE << (lod + 1)) as f32,
+                ];
+                triangles.extend(place(&geometry.seam, scale, 0.0, next_base));
+            }
+        }
+
+        (triangles, bounds)
+    }
+
+    #[test]
+    fn triangle_counts() {
+        let geometry = ClipmapGeometry::new(SIZE);
+        let s = SIZE as usize;
+        let expected = [
+            (MeshType::TileInner, 2 * s * s),
+            (MeshType::Tile, 4 * s * s),
+            (MeshType::FillerInner, 8 * s),
+            (MeshType::Filler, 16 * s),
+            (MeshType::TrimInner, 2 * (8 * s + 3)),
+            (MeshType::Trim, 4 * (8 * s + 3)),
+            (MeshType::Cross, 2 * (4 * s + 1)),
+            (MeshType::Seam, 2 * (4 * s + 2)),
+        ];
+        for (mesh_type, count) in expected {
+            assert_eq!(geometry.get(mesh_type).triangle_count(), count, "{:?}", mesh_type);
+        }
+    }
+
+    #[test]
+    fn clockwise_winding() {
+        let geometry = ClipmapGeometry::new(SIZE);
+        for mesh_type in MeshType::ALL {
+            let triangles = place(geometry.get(mesh_type), 1.0, 0.0, [0.0, 0.0]);
+            // The seam is all degenerate triangles, which have no winding
+            for tri in triangles.iter().filter(|tri| winding(tri) != 0) {
+                assert!(winding(tri) < 0, "{:?} triangle {:?} is not clockwise", mesh_type, tri);
+            }
+        }
+    }
+
+    #[test]
+    fn shading_samples_world_positions() {
+        let geometry = ClipmapGeometry::new(SIZE);
+        // A slope of 1 in X that only exists east of x = 100
+        let source = |x: f32, _z: f32| (x - 100.0).max(0.0);
+        let shading = geometry.tile.shading_from_heights(&source, 4.0, [200.0, -50.0]);
+        let expected = normalize([-1.0, 1.0, 0.0]);
+        for normal in &shading.normals {
+            assert!(length_squared(sub(*normal, expected)) < 1e-6, "normal {:?}", normal);
+        }
+        for (normal, tangent) in shading.normals.iter().zip(&shading.tangents) {
+            assert!(dot(*normal, [tangent[0], tangent[1], tangent[2]]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn morph_factors() {
+        let geometry = ClipmapGeometry::new(SIZE);
+        let half_extent = geometry.ring_half_extent();
+        let size = SIZE as f32;
+
+        // Pieces of one level at scale 1, placed like FastTerrain::snap
+        for position in [[0.0f32, 0.0], [1.5, 0.5], [0.5, 1.5], [-13.1, 22.6]] {
+            let snapped = [position[0].floor(), position[1].floor()];
+            let center = [snapped[0] + 0.5, snapped[1] + 0.5];
+            let base = [snapped[0] - size * 2.0, snapped[1] - size * 2.0];
+            let fill = |i: i32| if i >= 2 { 1.0 } else { 0.0 };
+
+            let mut pieces = Vec::new();
+            for x in 0..4 {
+                for y in 0..4 {
+                    let origin = [base[0] + x as f32 * size + fill(x), base[1] + y as f32 * size + fill(y)];
+                    pieces.push((MeshType::Tile, [x, y], 0.0, origin));
+                }
+            }
+            pieces.push((MeshType::Filler, [0, 0], 0.0, snapped));
+            for angle in [0.0, 90.0, 180.0, 270.0] {
+                pieces.push((MeshType::Trim, [0, 0], angle, center));
+            }
+
+            for (mesh_type, tile, angle, origin) in pieces {
+                let mesh = geometry.get(mesh_type);
+                let morph = mesh.morph_factors(geometry.ring_center(mesh_type, tile), half_extent);
+                for (vertex, factor) in mesh.vertices.iter().zip(&morph) {
+                    let [x, z] = place_vertex(*vertex, 1.0, angle, origin);
+                    let distance = (x - center[0]).abs().max((z - center[1]).abs());
+                    if distance <= half_extent * 0.5 {
+                        assert_eq!(*factor, [0.0, 0.0], "{:?} {:?} vertex {:?} inside the ring", mesh_type, tile, vertex);
+                    }
+                    if distance >= half_extent {
+                        assert_eq!(factor[0].max(factor[1]), 1.0, "{:?} {:?} vertex {:?} on the border", mesh_type, tile, vertex);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn watertight_rings() {
+        let geometry = ClipmapGeometry::new(SIZE);
+        for position in [[0.0, 0.0], [0.5, 0.5], [1.5, 0.5], [0.5, 1.5], [1.5, 1.5], [5.3, -7.8], [-13.1, 22.6]] {
+            let (triangles, bounds) = assemble(&geometry, 4, position);
+
+            let mut edges: HashMap<(Point, Point), u32> = HashMap::new();
+            for tri in &triangles {
+                for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+                    *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+                }
+            }
+
+            let [min_x, min_z, max_x, max_z] = bounds.map(|value| (value * 2.0).round() as i32);
+            let on_border = |(x, z): Point| x == min_x || x == max_x || z == min_z || z == max_z;
+            for (&(a, b), &count) in &edges {
+                assert!(count <= 2, "edge {:?} -> {:?} is shared by {} triangles at {:?}", a, b, count, position);
+                if count == 1 {
+                    let same_side = (a.0 == b.0 && (a.0 == min_x || a.0 == max_x))
+                        || (a.1 == b.1 && (a.1 == min_z || a.1 == max_z));
+                    assert!(
+                        on_border(a) && on_border(b) && same_side,
+                        "open edge {:?} -> {:?} inside the rings at {:?}",
+                        a,
+                        b,
+                        position
+                    );
+                }
+            }
+        }
+    }
+}
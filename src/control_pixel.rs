@@ -0,0 +1,225 @@
+use godot::prelude::*;
+
+// One control map pixel. The RF control map stores these bits reinterpreted as an f32
+//
+// Bits, high to low: base 5 | overlay 5 | blend 8 | uv rotation 4 | uv scale 3 | reserved 4 | hole 1 | nav 1 | auto 1
+#[derive(GodotConvert, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[godot(transparent)]
+pub struct ControlPixel(u32);
+
+impl ControlPixel {
+    const BASE_SHIFT: u32 = 27;
+    const OVERLAY_SHIFT: u32 = 22;
+    const BLEND_SHIFT: u32 = 14;
+    const UV_ROTATION_SHIFT: u32 = 10;
+    const UV_SCALE_SHIFT: u32 = 7;
+    const HOLE_SHIFT: u32 = 2;
+    const NAV_SHIFT: u32 = 1;
+    const AUTO_SHIFT: u32 = 0;
+
+    pub const fn from_u32(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn to_u32(self) -> u32 {
+        self.0
+    }
+
+    pub fn from_f32(value: f32) -> Self {
+        Self(value.to_bits())
+    }
+
+    pub fn to_f32(self) -> f32 {
+        f32::from_bits(self.0)
+    }
+
+    pub fn from_color(color: Color) -> Self {
+        Self::from_f32(color.r)
+    }
+
+    pub fn to_color(self) -> Color {
+        Color::from_rgba(self.to_f32(), 0.0, 0.0, 1.0)
+    }
+
+    fn get(self, shift: u32, mask: u32) -> u32 {
+        (self.0 >> shift) & mask
+    }
+
+    fn with(self, shift: u32, mask: u32, value: u32) -> Self {
+        Self((self.0 & !(mask << shift)) | ((value & mask) << shift))
+    }
+
+    pub fn base(self) -> u8 {
+        self.get(Self::BASE_SHIFT, 0x1F) as u8
+    }
+
+    pub fn with_base(self, base: u8) -> Self {
+        self.with(Self::BASE_SHIFT, 0x1F, base as u32)
+    }
+
+    pub fn overlay(self) -> u8 {
+        self.get(Self::OVERLAY_SHIFT, 0x1F) as u8
+    }
+
+    pub fn with_overlay(self, overlay: u8) -> Self {
+        self.with(Self::OVERLAY_SHIFT, 0x1F, overlay as u32)
+    }
+
+    pub fn blend(self) -> u8 {
+        self.get(Self::BLEND_SHIFT, 0xFF) as u8
+    }
+
+    pub fn with_blend(self, blend: u8) -> Self {
+        self.with(Self::BLEND_SHIFT, 0xFF, blend as u32)
+    }
+
+    pub fn uv_rotation(self) -> u8 {
+        self.get(Self::UV_ROTATION_SHIFT, 0xF) as u8
+    }
+
+    pub fn with_uv_rotation(self, rotation: u8) -> Self {
+        self.with(Self::UV_ROTATION_SHIFT, 0xF, rotation as u32)
+    }
+
+    pub fn uv_scale(self) -> u8 {
+        self.get(Self::UV_SCALE_SHIFT, 0x7) as u8
+    }
+
+    pub fn with_uv_scale(self, scale: u8) -> Self {
+        self.with(Self::UV_SCALE_SHIFT, 0x7, scale as u32)
+    }
+
+    pub fn is_hole(self) -> bool {
+        self.get(Self::HOLE_SHIFT, 0x1) == 1
+    }
+
+    pub fn with_hole(self, hole: bool) -> Self {
+        self.with(Self::HOLE_SHIFT, 0x1, hole as u32)
+    }
+
+    pub fn is_nav(self) -> bool {
+        self.get(Self::NAV_SHIFT, 0x1) == 1
+    }
+
+    pub fn with_nav(self, nav: bool) -> Self {
+        self.with(Self::NAV_SHIFT, 0x1, nav as u32)
+    }
+
+    pub fn is_auto(self) -> bool {
+        self.get(Self::AUTO_SHIFT, 0x1) == 1
+    }
+
+    pub fn with_auto(self, auto: bool) -> Self {
+        self.with(Self::AUTO_SHIFT, 0x1, auto as u32)
+    }
+}
+
+impl From<u32> for ControlPixel {
+    fn from(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<ControlPixel> for u32 {
+    fn from(pixel: ControlPixel) -> Self {
+        pixel.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every field set to a value different from its neighbours, so shifted bits show up
+    fn sample_pixel() -> ControlPixel {
+        ControlPixel::default()
+            .with_base(21)
+            .with_overlay(10)
+            .with_blend(0xA5)
+            .with_uv_rotation(9)
+            .with_uv_scale(5)
+            .with_hole(true)
+            .with_nav(false)
+            .with_auto(true)
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        let pixel = sample_pixel();
+        assert_eq!(
+            (pixel.base(), pixel.overlay(), pixel.blend(), pixel.uv_rotation(), pixel.uv_scale()),
+            (21, 10, 0xA5, 9, 5)
+        );
+        assert!(pixel.is_hole() && !pixel.is_nav() && pixel.is_auto());
+
+        // Each setter changes its own field and nothing else
+        let changes = [
+            pixel.with_base(3),
+            pixel.with_overlay(30),
+            pixel.with_blend(0x0F),
+            pixel.with_uv_rotation(2),
+            pixel.with_uv_scale(1),
+            pixel.with_hole(false),
+            pixel.with_nav(true),
+            pixel.with_auto(false),
+        ];
+        for (field, changed) in changes.iter().enumerate() {
+            let fields = |p: ControlPixel| {
+                [
+                    p.base() as u32,
+                    p.overlay() as u32,
+                    p.blend() as u32,
+                    p.uv_rotation() as u32,
+                    p.uv_scale() as u32,
+                    p.is_hole() as u32,
+                    p.is_nav() as u32,
+                    p.is_auto() as u32,
+                ]
+            };
+            let (before, after) = (fields(pixel), fields(*changed));
+            for i in 0..before.len() {
+                assert_eq!(before[i] != after[i], i == field, "setting field {} changed field {}", field, i);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_masked() {
+        let pixel = sample_pixel();
+        assert_eq!(pixel.with_base(40), pixel.with_base(40 & 0x1F));
+        assert_eq!(pixel.with_base(40).base(), 8);
+        assert_eq!(pixel.with_overlay(0xFF).overlay(), 0x1F);
+        assert_eq!(pixel.with_uv_rotation(0x13).uv_rotation(), 0x3);
+        assert_eq!(pixel.with_uv_scale(0xF).uv_scale(), 0x7);
+        assert_eq!(pixel.with_base(40).overlay(), pixel.overlay());
+        assert_eq!(pixel.with_uv_scale(0xF).to_u32() & 0x78, pixel.to_u32() & 0x78, "reserved bits changed");
+    }
+
+    #[test]
+    fn u32_round_trip() {
+        for bits in [0, 1, 0x8000_0000, 0xFFFF_FFFF, 0x1234_5678, sample_pixel().to_u32()] {
+            assert_eq!(ControlPixel::from_u32(bits).to_u32(), bits);
+            assert_eq!(u32::from(ControlPixel::from(bits)), bits);
+        }
+        // Matches the layout the shader decodes
+        let expected = (21 << 27) | (10 << 22) | (0xA5 << 14) | (9 << 10) | (5 << 7) | (1 << 2) | 1;
+        assert_eq!(sample_pixel().to_u32(), expected);
+    }
+
+    #[test]
+    fn f32_round_trip_keeps_nan_and_inf_bits() {
+        // Base 31 with overlay 30 fills the exponent, which is -Inf on its own. Any bit below,
+        // like the hole bit, turns it into a NaN, and without overlay bit 0 a signalling one
+        let infinity = ControlPixel::default().with_base(31).with_overlay(30);
+        let nan = infinity.with_hole(true);
+        let quiet_nan = nan.with_overlay(31);
+        assert!(infinity.to_f32().is_infinite());
+        assert!(nan.to_f32().is_nan() && quiet_nan.to_f32().is_nan());
+
+        for pixel in [infinity, nan, quiet_nan, sample_pixel(), ControlPixel::from_u32(0xFFFF_FFFF)] {
+            assert_eq!(ControlPixel::from_f32(pixel.to_f32()), pixel);
+            assert_eq!(ControlPixel::from_color(pixel.to_color()), pixel);
+        }
+        assert!(ControlPixel::from_f32(nan.to_f32()).is_hole());
+    }
+}
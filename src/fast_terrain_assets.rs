@@ -1,12 +1,18 @@
-use godot::classes::{ImageTexture, RenderingServer, ResourceSaver};
+use godot::classes::image::Format;
+use godot::classes::rendering_server::ViewportUpdateMode;
+use godot::classes::{DisplayServer, Image, ImageTexture, RenderingServer, ResourceSaver};
 use godot::global::Error;
+use godot::classes::Mesh;
 use godot::prelude::*;
 
-use crate::fast_terrain_texture_asset::FastTerrainTextureAsset;
+use crate::fast_terrain_assets_resource::FastTerrainAssetResource;
 use crate::{
     fast_terrain_texture_asset::FastTerrainTextureAsset,
+    fast_terrain_instancer::FastTerrainInstancer,
     fast_terrain_mesh_asset::FastTerrainMeshAsset,
+    fast_terrain_util::FastTerrainUtil,
     generated_texture::GeneratedTexture,
+    FastTerrain,
 };
 
 pub const MAX_TEXTURES: i32 = 32;  // Updated from 16 to 32
@@ -32,9 +38,9 @@ pub struct FastTerrainAssets {
     fill_light_instance: Rid,
     mesh_instance: Rid,
 
-    // Generated textures
-    generated_albedo_textures: Option<Gd<ImageTexture>>,
-    generated_normal_textures: Option<Gd<ImageTexture>>,
+    // Generated Texture2DArrays, one layer per texture id
+    generated_albedo_textures: Gd<GeneratedTexture>,
+    generated_normal_textures: Gd<GeneratedTexture>,
     
     // Texture arrays
     texture_colors: PackedColorArray,
@@ -73,8 +79,8 @@ impl IResource for FastTerrainAssets {
             fill_light: Rid::new(0),
             fill_light_instance: Rid::new(0),
             mesh_instance: Rid::new(0),
-            generated_albedo_textures: None,
-            generated_normal_textures: None,
+            generated_albedo_textures: GeneratedTexture::new(),
+            generated_normal_textures: GeneratedTexture::new(),
             texture_colors: PackedColorArray::new(),
             texture_uv_scales: PackedFloat32Array::new(),
             texture_detiles: PackedFloat32Array::new(),
@@ -89,13 +95,24 @@ impl FastTerrainAssets {
     pub fn initialize(&mut self, terrain: Gd<FastTerrain>) {
         self.terrain = Some(terrain);
         
-        let rs = RenderingServer::singleton();
+        // Reassigned or shared assets keep the preview environment they already have
+        if !self.scenario.is_valid() {
+            self.setup_preview();
+        }
+
+        // Initial updates
+        self.update_texture_list();
+        self.update_mesh_list();
+    }
+
+    fn setup_preview(&mut self) {
+        let mut rs = RenderingServer::singleton();
         
         // Setup preview environment
         self.scenario = rs.scenario_create();
         
         self.viewport = rs.viewport_create();
-        rs.viewport_set_update_mode(self.viewport, RenderingServer::VIEWPORT_UPDATE_DISABLED);
+        rs.viewport_set_update_mode(self.viewport, ViewportUpdateMode::DISABLED);
         rs.viewport_set_scenario(self.viewport, self.scenario);
         rs.viewport_set_size(self.viewport, 128, 128);
         rs.viewport_set_transparent_background(self.viewport, true);
@@ -106,30 +123,27 @@ impl FastTerrainAssets {
         // Setup camera
         self.camera = rs.camera_create();
         rs.viewport_attach_camera(self.viewport, self.camera);
-        rs.camera_set_transform(self.camera, Transform3D::from_basis_origin(
+        rs.camera_set_transform(self.camera, Transform3D::new(
             Basis::IDENTITY,
             Vector3::new(0.0, 0.0, 3.0)
         ));
         rs.camera_set_orthogonal(self.camera, 1.0, 0.01, 1000.0);
 
         // Setup lights
-        self.setup_lights(&rs);
+        self.setup_lights(&mut rs);
         
         self.mesh_instance = rs.instance_create();
         rs.instance_set_scenario(self.mesh_instance, self.scenario);
-
-        // Initial updates
-        self.update_texture_list();
-        self.update_mesh_list();
     }
 
-    fn setup_lights(&mut self, rs: &RenderingServer) {
+    fn setup_lights(&mut self, rs: &mut RenderingServer) {
         self.key_light = rs.directional_light_create();
         self.key_light_instance = rs.instance_create2(self.key_light, self.scenario);
         
         let key_transform = Transform3D::IDENTITY.looking_at(
             Vector3::new(-1.0, -1.0, -1.0),
-            Vector3::UP
+            Vector3::UP,
+            false
         );
         rs.instance_set_transform(self.key_light_instance, key_transform);
 
@@ -139,44 +153,164 @@ impl FastTerrainAssets {
         
         let fill_transform = Transform3D::IDENTITY.looking_at(
             Vector3::UP,
-            Vector3::FORWARD
+            Vector3::FORWARD,
+            false
         );
         rs.instance_set_transform(self.fill_light_instance, fill_transform);
     }
 
     #[func]
     pub fn update_texture_list(&mut self) {
-        // Implementation for texture list update
-        self.generated_albedo_textures = None;
-        self.generated_normal_textures = None;
         self.update_texture_files();
         self.update_texture_settings();
-        self.base.emit_signal("textures_changed".into(), &[]);
+        self.base_mut().emit_signal("textures_changed", &[]);
     }
 
+    // Rebuilds the albedo and normal arrays. Every texture of a kind must share size,
+    // format and mipmaps. Missing textures are filled with a checkerboard or flat normal
+    #[func]
     fn update_texture_files(&mut self) {
-        // Implementation for updating texture files
-        // This would handle texture array generation and validation
+        godot_print!("Generating texture arrays for {} textures", self.texture_list.len());
+        if self.texture_list.is_empty() {
+            self.generated_albedo_textures.bind_mut().clear();
+            self.generated_normal_textures.bind_mut().clear();
+            return;
+        }
+
+        let albedo_images: Vec<Option<Gd<Image>>> = self
+            .texture_list
+            .iter()
+            .map(|texture| texture.bind().get_albedo_texture().and_then(|tex| tex.get_image()))
+            .collect();
+        let normal_images: Vec<Option<Gd<Image>>> = self
+            .texture_list
+            .iter()
+            .map(|texture| texture.bind().get_normal_texture().and_then(|tex| tex.get_image()))
+            .collect();
+
+        let albedo_layers = Self::build_layers("albedo", &albedo_images, Color::from_rgba(1.0, 1.0, 1.0, -1.0));
+        let normal_layers = Self::build_layers("normal", &normal_images, Color::from_rgba(0.5, 0.5, 1.0, 1.0));
+        match (albedo_layers, normal_layers) {
+            (Some(albedo_layers), Some(normal_layers)) => {
+                self.generated_albedo_textures.bind_mut().create_from_layers(albedo_layers);
+                self.generated_normal_textures.bind_mut().create_from_layers(normal_layers);
+            }
+            _ => {
+                godot_error!("Texture arrays not generated. Fix the textures above");
+                self.generated_albedo_textures.bind_mut().clear();
+                self.generated_normal_textures.bind_mut().clear();
+            }
+        }
     }
 
+    #[func]
+    fn on_texture_file_changed(&mut self) {
+        self.update_texture_files();
+        self.base_mut().emit_signal("textures_changed", &[]);
+    }
+
+    // Fills the per id shader arrays, padded to MAX_TEXTURES
     fn update_texture_settings(&mut self) {
-        if !self.texture_list.is_empty() {
-            self.texture_colors.clear();
-            self.texture_uv_scales.clear();
-            self.texture_detiles.clear();
-
-            for texture_set in &self.texture_list {
-                // Update arrays with texture settings
-                // Implementation details would go here
-            }
+        self.texture_colors.clear();
+        self.texture_uv_scales.clear();
+        self.texture_detiles.clear();
+        self.texture_colors.resize(MAX_TEXTURES as usize);
+        self.texture_uv_scales.resize(MAX_TEXTURES as usize);
+        self.texture_detiles.resize(MAX_TEXTURES as usize);
+
+        for id in 0..MAX_TEXTURES as usize {
+            self.update_texture_slot(id);
         }
-        self.base.emit_signal("textures_changed".into(), &[]);
     }
 
+    // Only the changed texture's slot is refreshed. The texture arrays are left alone
+    #[func]
+    fn on_texture_setting_changed(&mut self, texture: Gd<FastTerrainTextureAsset>) {
+        let Some(id) = self.texture_list.iter().position(|listed| *listed == texture) else {
+            return;
+        };
+        if self.texture_colors.len() != MAX_TEXTURES as usize {
+            self.update_texture_settings();
+        } else {
+            self.update_texture_slot(id);
+        }
+        self.base_mut().emit_signal("textures_changed", &[]);
+    }
+
+    // Renders the mesh asset with the given id, or every mesh asset if id is -1, into the
+    // preview viewport and stores the result as its thumbnail. Headless and dummy renderers
+    // produce nothing to read back, so those get a checkerboard placeholder
     #[func]
     pub fn create_mesh_thumbnails(&mut self, id: i32, size: Vector2i) {
-        // Implementation for mesh thumbnail generation
-        // This would use the viewport setup to render previews
+        let size = Vector2i::new(size.x.clamp(1, 4096), size.y.clamp(1, 4096));
+        let ids: Vec<usize> = if id < 0 {
+            (0..self.mesh_list.len()).collect()
+        } else if (id as usize) < self.mesh_list.len() {
+            vec![id as usize]
+        } else {
+            godot_error!("Mesh id {} out of range 0-{}", id, self.mesh_list.len() as i32 - 1);
+            return;
+        };
+        if !self.viewport.is_valid() {
+            godot_error!("Assets not initialized. Cannot create thumbnails");
+            return;
+        }
+
+        let headless = DisplayServer::singleton().get_name() == "headless".into();
+        godot_print!("Creating {} mesh thumbnails of size {}, headless: {}", ids.len(), size, headless);
+        let mut rs = RenderingServer::singleton();
+        rs.viewport_set_size(self.viewport, size.x, size.y);
+
+        for id in ids {
+            let mut mesh_asset = self.mesh_list[id].clone();
+            let Some(mesh) = mesh_asset.bind().get_mesh(0) else {
+                godot_print!("Mesh id {} has no mesh. Skipping thumbnail", id);
+                mesh_asset.bind_mut().set_thumbnail(None);
+                continue;
+            };
+
+            let image = if headless {
+                None
+            } else {
+                self.render_thumbnail(&mut rs, &mesh)
+            };
+            let image = image
+                .filter(|image| !image.is_empty())
+                .or_else(|| FastTerrainUtil::get_filled_image(size, Color::from_rgba(1.0, 1.0, 1.0, -1.0), false, Format::RGBA8));
+            let thumbnail = image.and_then(|image| ImageTexture::create_from_image(&image));
+            mesh_asset.bind_mut().set_thumbnail(thumbnail);
+        }
+        rs.instance_set_base(self.mesh_instance, Rid::Invalid);
+    }
+
+    #[func]
+    pub fn set_texture_list(&mut self, texture_list: Array<Gd<FastTerrainTextureAsset>>) {
+        godot_print!("Setting texture list with {} entries", texture_list.len());
+        let callable = self.base().callable("on_texture_file_changed");
+        let setting_callable = self.base().callable("on_texture_setting_changed");
+        for texture in self.texture_list.iter_mut() {
+            if texture.is_connected("file_changed", &callable) {
+                texture.disconnect("file_changed", &callable);
+            }
+            let bound = setting_callable.bind(&[texture.to_variant()]);
+            if texture.is_connected("setting_changed", &bound) {
+                texture.disconnect("setting_changed", &bound);
+            }
+        }
+
+        self.texture_list = texture_list
+            .iter_shared()
+            .take(MAX_TEXTURES as usize)
+            .collect();
+        for (i, texture) in self.texture_list.iter_mut().enumerate() {
+            if texture.bind().get_id() != i as i32 {
+                texture.bind_mut().set_id(i as i32);
+            }
+            texture.connect("file_changed", &callable);
+            let bound = setting_callable.bind(&[texture.to_variant()]);
+            texture.connect("setting_changed", &bound);
+        }
+        self.update_texture_list();
     }
 
     #[func]
@@ -186,7 +320,7 @@ impl FastTerrainAssets {
 
     #[func]
     pub fn get_texture_list(&self) -> Array<Gd<FastTerrainTextureAsset>> {
-        self.texture_list.clone().into()
+        self.texture_list.iter().cloned().collect()
     }
 
     #[func]
@@ -196,16 +330,12 @@ impl FastTerrainAssets {
 
     #[func]
     pub fn get_albedo_array_rid(&self) -> Rid {
-        self.generated_albedo_textures.as_ref()
-            .map(|tex| tex.get_rid())
-            .unwrap_or_default()
+        self.generated_albedo_textures.bind().get_rid()
     }
 
     #[func]
     pub fn get_normal_array_rid(&self) -> Rid {
-        self.generated_normal_textures.as_ref()
-            .map(|tex| tex.get_rid())
-            .unwrap_or_default()
+        self.generated_normal_textures.bind().get_rid()
     }
 
     #[func]
@@ -225,12 +355,62 @@ impl FastTerrainAssets {
 
     #[func]
     pub fn set_mesh_list(&mut self, mesh_list: Array<Gd<FastTerrainMeshAsset>>) {
-        self._set_asset_list(AssetType::Mesh, mesh_list);
+        godot_print!("Setting mesh list with {} entries", mesh_list.len());
+        let callable = self.base().callable("on_mesh_setting_changed");
+        for mesh_asset in self.mesh_list.iter_mut() {
+            if mesh_asset.is_connected("instancer_setting_changed", &callable) {
+                mesh_asset.disconnect("instancer_setting_changed", &callable);
+            }
+        }
+
+        self.mesh_list = mesh_list
+            .iter_shared()
+            .take(MAX_MESHES as usize)
+            .collect();
+        for mesh_asset in self.mesh_list.iter_mut() {
+            mesh_asset.connect("instancer_setting_changed", &callable);
+        }
+        self.update_mesh_list();
+        if self.viewport.is_valid() {
+            self.create_mesh_thumbnails(-1, Vector2i::new(128, 128));
+        }
+    }
+
+    #[func]
+    fn on_mesh_setting_changed(&mut self) {
+        self.base_mut().emit_signal("meshes_changed", &[]);
+    }
+
+    #[func]
+    pub fn get_mesh_asset(&self, id: i32) -> Option<Gd<FastTerrainMeshAsset>> {
+        if id < 0 {
+            return None;
+        }
+        self.mesh_list.get(id as usize).cloned()
+    }
+
+    // Removes a mesh asset. Instances of it are deleted and higher ids shift down
+    #[func]
+    pub fn remove_mesh(&mut self, id: i32) {
+        if id < 0 || id >= self.mesh_list.len() as i32 {
+            godot_error!("Mesh id {} out of range 0-{}", id, self.mesh_list.len() as i32 - 1);
+            return;
+        }
+        godot_print!("Removing mesh asset id: {}", id);
+        let mut mesh_asset = self.mesh_list.remove(id as usize);
+        let callable = self.base().callable("on_mesh_setting_changed");
+        if mesh_asset.is_connected("instancer_setting_changed", &callable) {
+            mesh_asset.disconnect("instancer_setting_changed", &callable);
+        }
+        if let Some(mut instancer) = self.get_instancer() {
+            instancer.bind_mut().remove_mesh_id(id);
+        }
+        self.update_mesh_list();
     }
 
     #[func]
     pub fn get_mesh_list(&self) -> Array<Gd<FastTerrainMeshAsset>> {
-        self.mesh_list.clone().into()
+        self.mesh_list.iter().cloned().collect()
     }
 
     #[func]
@@ -241,35 +421,52 @@ impl FastTerrainAssets {
     fn _swap_ids(&mut self, asset_type: AssetType, src_id: i32, dst_id: i32) {
         godot_print!("Swapping asset id: {} and id: {}", src_id, dst_id);
         
-        let list = match asset_type {
-            AssetType::Texture => &mut self.texture_list,
-            AssetType::Mesh => &mut self.mesh_list,
+        let list_len = match asset_type {
+            AssetType::Texture => self.texture_list.len(),
+            AssetType::Mesh => self.mesh_list.len(),
         };
 
-        if src_id < 0 || src_id >= list.len() as i32 {
+        if src_id < 0 || src_id >= list_len as i32 {
             godot_print!("Source id out of range: {}", src_id);
             return;
         }
 
-        let dst_id = dst_id.clamp(0, (list.len() - 1) as i32);
+        let dst_id = dst_id.clamp(0, (list_len - 1) as i32);
         if dst_id == src_id {
             return;
         }
 
-        list.swap(src_id as usize, dst_id as usize);
-        
         match asset_type {
-            AssetType::Texture => self.update_texture_list(),
+            AssetType::Texture => {
+                self.texture_list.swap(src_id as usize, dst_id as usize);
+                self.update_texture_list();
+            }
             AssetType::Mesh => {
-                if let Some(terrain) = &self.terrain {
-                    // Implement swap_ids for instancer when available
-                    // terrain.get_instancer().swap_ids(src_id, dst_id);
+                self.mesh_list.swap(src_id as usize, dst_id as usize);
+                if let Some(mut instancer) = self.get_instancer() {
+                    instancer.bind_mut().swap_ids(src_id, dst_id);
                 }
                 self.update_mesh_list();
             }
         }
     }
 
+    #[func]
+    pub fn update_mesh_list(&mut self) {
+        for (i, mesh_asset) in self.mesh_list.iter_mut().enumerate() {
+            if mesh_asset.bind().get_id() != i as i32 {
+                mesh_asset.bind_mut().set_id(i as i32);
+            }
+        }
+        self.base_mut().emit_signal("meshes_changed", &[]);
+    }
+
+    #[signal]
+    fn textures_changed();
+
+    #[signal]
+    fn meshes_changed();
+
     #[func]
     pub fn save(&self, path: GString) -> Error {
         if path.is_empty() && self.base().get_path().is_empty() {
@@ -296,6 +493,124 @@ impl FastTerrainAssets {
     }
 }
 
+impl FastTerrainAssets {
+    fn get_instancer(&self) -> Option<Gd<FastTerrainInstancer>> {
+        self.terrain.as_ref()?.bind().get_instancer()
+    }
+
+    // Frames the camera on the mesh AABB from the front and slightly above, then draws once
+    fn render_thumbnail(&self, rs: &mut RenderingServer, mesh: &Gd<Mesh>) -> Option<Gd<Image>> {
+        let aabb = mesh.get_aabb();
+        let center = aabb.center();
+        let radius = (aabb.size.length() * 0.5).max(0.001);
+        let direction = Vector3::new(0.5, 0.35, 1.0).normalized();
+        let camera_transform = Transform3D::new(Basis::IDENTITY, center + direction * radius * 3.0)
+            .looking_at(center, Vector3::UP, false);
+
+        rs.instance_set_base(self.mesh_instance, mesh.get_rid());
+        rs.camera_set_transform(self.camera, camera_transform);
+        rs.camera_set_orthogonal(self.camera, radius * 2.0, 0.01, radius * 6.0);
+        rs.viewport_set_update_mode(self.viewport, ViewportUpdateMode::ONCE);
+        rs.force_draw_ex().swap_buffers(false).done();
+        rs.texture_2d_get(self.viewport_texture)
+    }
+
+    fn update_texture_slot(&mut self, id: usize) {
+        let (color, uv_scale, detiling) = match self.texture_list.get(id) {
+            Some(texture) => {
+                let texture = texture.bind();
+                (texture.get_albedo_color(), texture.get_uv_scale(), texture.get_detiling())
+            }
+            None => (Color::from_rgba(1.0, 1.0, 1.0, 1.0), 0.1, 0.0),
+        };
+        self.texture_colors[id] = color;
+        self.texture_uv_scales[id] = uv_scale;
+        self.texture_detiles[id] = detiling;
+    }
+
+    // Validates one kind of texture and fills the empty slots. None if any texture differs
+    // from the first in size, format or mipmaps. If no filler can be made in the textures'
+    // compressed format, the textures are decompressed and the array is built as RGBA8
+    fn build_layers(kind: &str, images: &[Option<Gd<Image>>], fill_color: Color) -> Option<Array<Gd<Image>>> {
+        let first = images.iter().enumerate().find_map(|(id, image)| Some((id, image.as_ref()?)));
+        let (size, format, mipmaps) = match first {
+            Some((_, image)) => (image.get_size(), image.get_format(), image.has_mipmaps()),
+            None => (Vector2i::new(256, 256), Format::RGBA8, true),
+        };
+
+        let mut valid = true;
+        for (id, image) in images.iter().enumerate() {
+            let Some(image) = image else {
+                continue;
+            };
+            let first_id = first.map_or(0, |(first_id, _)| first_id);
+            if image.get_size() != size {
+                godot_error!(
+                    "Texture ID {} {} size {} doesn't match size {} of texture ID {}",
+                    id, kind, image.get_size(), size, first_id
+                );
+                valid = false;
+            }
+            if image.get_format() != format {
+                godot_error!(
+                    "Texture ID {} {} format {:?} doesn't match format {:?} of texture ID {}",
+                    id, kind, image.get_format(), format, first_id
+                );
+                valid = false;
+            }
+            if image.has_mipmaps() != mipmaps {
+                godot_error!(
+                    "Texture ID {} {} mipmaps {} don't match mipmaps {} of texture ID {}",
+                    id, kind, image.has_mipmaps(), mipmaps, first_id
+                );
+                valid = false;
+            }
+        }
+        if !valid {
+            return None;
+        }
+
+        let missing: Vec<usize> = images
+            .iter()
+            .enumerate()
+            .filter_map(|(id, image)| image.is_none().then_some(id))
+            .collect();
+        let mut layer_format = format;
+        let mut filler = None;
+        if !missing.is_empty() {
+            filler = FastTerrainUtil::get_filled_image(size, fill_color, mipmaps, format)
+                .filter(|filled| filled.get_format() == format);
+            if filler.is_none() {
+                godot_error!(
+                    "Texture IDs {:?} have no {} texture and a {:?} filler can't be made here. Building the {} array uncompressed",
+                    missing, kind, format, kind
+                );
+                layer_format = Format::RGBA8;
+                filler = FastTerrainUtil::get_filled_image(size, fill_color, mipmaps, layer_format);
+            }
+        }
+
+        let mut layers = Array::new();
+        for image in images {
+            let image = match image {
+                Some(image) if image.get_format() == layer_format => image.clone(),
+                Some(image) => {
+                    let mut converted = Image::new_gd();
+                    converted.copy_from(image);
+                    if converted.is_compressed() {
+                        converted.decompress();
+                    }
+                    converted.convert(layer_format);
+                    converted
+                }
+                None => filler.clone()?,
+            };
+            layers.push(&image);
+        }
+        Some(layers)
+    }
+}
+
 impl Drop for FastTerrainAssets {
     fn drop(&mut self) {
         let mut rs = RenderingServer::singleton();
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use godot::{
+    classes::{image::Format, physics_server_3d::BodyMode, Engine, Image, PhysicsServer3D},
+    prelude::*,
+};
+
+use crate::{
+    control_pixel::ControlPixel,
+    fast_terrain_data::FastTerrainData,
+    fast_terrain_region::MapType,
+};
+
+#[derive(GodotConvert, Var, Export, Clone, Copy, PartialEq, Debug)]
+#[godot(via = GString)]
+pub enum CollisionMode {
+    Disabled,
+    // Every region, in game and editor
+    Full,
+    // Only regions within collision_radius of the clipmap target
+    Radius,
+    // Every region, but only while running in the editor
+    EditorOnly,
+}
+
+// Shape of one colliding region and the heights it was last given, so edits only reread
+// the rows and columns they touch
+struct RegionShape {
+    shape: Rid,
+    heights: Vec<f32>,
+}
+
+// One static body with a HeightMapShape3D per colliding region
+pub struct FastTerrainCollision {
+    body: Rid,
+    shapes: HashMap<Vector2i, RegionShape>,
+}
+
+impl FastTerrainCollision {
+    pub fn new() -> Self {
+        Self {
+            body: Rid::Invalid,
+            shapes: HashMap::new(),
+        }
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.body.is_valid()
+    }
+
+    pub fn build(&mut self, space: Rid, instance_id: InstanceId) {
+        if self.is_built() {
+            return;
+        }
+
+        godot_print!("Building collision body");
+        let mut ps = PhysicsServer3D::singleton();
+        self.body = ps.body_create();
+        ps.body_set_mode(self.body, BodyMode::STATIC);
+        ps.body_set_space(self.body, space);
+        ps.body_attach_object_instance_id(self.body, instance_id.to_i64() as u64);
+    }
+
+    pub fn destroy(&mut self) {
+        if !self.is_built() {
+            return;
+        }
+
+        godot_print!("Destroying collision body");
+        self.clear_shapes();
+        PhysicsServer3D::singleton().free_rid(self.body);
+        self.body = Rid::Invalid;
+    }
+
+    pub fn clear_shapes(&mut self) {
+        let mut ps = PhysicsServer3D::singleton();
+        for (_, region_shape) in self.shapes.drain() {
+            // Freeing a shape also removes it from the body
+            ps.free_rid(region_shape.shape);
+        }
+    }
+
+    // Regions that should collide for the given mode and target position
+    pub fn active_regions(
+        mode: CollisionMode,
+        data: &FastTerrainData,
+        target_position: Option<Vector3>,
+        radius: f32,
+    ) -> Vec<Vector2i> {
+        let locations = data.get_region_locations().iter_shared().collect::<Vec<_>>();
+        match mode {
+            CollisionMode::Disabled => Vec::new(),
+            CollisionMode::Full => locations,
+            CollisionMode::EditorOnly if Engine::singleton().is_editor_hint() => locations,
+            CollisionMode::EditorOnly => Vec::new(),
+            CollisionMode::Radius => {
+                let Some(target) = target_position else {
+                    return Vec::new();
+                };
+                let region_width = data.get_region_size() as f32 * data.get_vertex_spacing();
+                locations
+                    .into_iter()
+                    .filter(|location| {
+                        // Distance from the target to the closest point of the region
+                        let min = Vector2::new(location.x as f32, location.y as f32) * region_width;
+                        let closest = Vector2::new(
+                            target.x.clamp(min.x, min.x + region_width),
+                            target.z.clamp(min.y, min.y + region_width),
+                        );
+                        (closest - Vector2::new(target.x, target.z)).length() <= radius
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // Adds and removes region shapes so exactly the given regions collide
+    pub fn sync(&mut self, data: &FastTerrainData, regions: &[Vector2i]) {
+        if !self.is_built() {
+            return;
+        }
+
+        let mut ps = PhysicsServer3D::singleton();
+        let stale: Vec<Vector2i> = self
+            .shapes
+            .keys()
+            .filter(|location| !regions.contains(location))
+            .copied()
+            .collect();
+        for location in stale {
+            if let Some(region_shape) = self.shapes.remove(&location) {
+                godot_print!("Removing collision for region {}", location);
+                ps.free_rid(region_shape.shape);
+            }
+        }
+
+        for location in regions {
+            if self.shapes.contains_key(location) {
+                continue;
+            }
+            godot_print!("Adding collision for region {}", location);
+            let shape_size = data.get_region_size() + 1;
+            let mut heights = vec![0.0; (shape_size * shape_size) as usize];
+            Self::read_heights(data, *location, &mut heights, Rect2i::new(Vector2i::ZERO, Vector2i::splat(shape_size)));
+
+            let shape = ps.heightmap_shape_create();
+            ps.shape_set_data(shape, &Self::shape_data(&heights, shape_size).to_variant());
+            ps.body_add_shape_ex(self.body, shape)
+                .transform(Self::region_transform(data, *location))
+                .done();
+            self.shapes.insert(*location, RegionShape { shape, heights });
+        }
+    }
+
+    // Refreshes the heights of every colliding region touched by the edited area
+    pub fn update_area(&mut self, data: &FastTerrainData, edited_area: Aabb) {
+        if !self.is_built() || self.shapes.is_empty() {
+            return;
+        }
+
+        // Grow by a vertex so neighbours sharing the edited edge are refreshed too
+        let spacing = data.get_vertex_spacing();
+        let start = data.get_region_location(edited_area.position - Vector3::new(spacing, 0.0, spacing));
+        let end = data.get_region_location(edited_area.end() + Vector3::new(spacing, 0.0, spacing));
+        let region_size = data.get_region_size();
+        let shape_size = region_size + 1;
+        let first_vertex = Vector2i::new(
+            (edited_area.position.x / spacing).floor() as i32 - 1,
+            (edited_area.position.z / spacing).floor() as i32 - 1,
+        );
+        let last_vertex = Vector2i::new(
+            (edited_area.end().x / spacing).ceil() as i32 + 1,
+            (edited_area.end().z / spacing).ceil() as i32 + 1,
+        );
+
+        let mut ps = PhysicsServer3D::singleton();
+        for z in start.y..=end.y {
+            for x in start.x..=end.x {
+                let location = Vector2i::new(x, z);
+                let Some(region_shape) = self.shapes.get_mut(&location) else {
+                    continue;
+                };
+                // Edited vertices in shape coordinates, including the borrowed edges
+                let origin = location * region_size;
+                let rect = Rect2i::from_corners(
+                    (first_vertex - origin).clamp(Vector2i::ZERO, Vector2i::splat(shape_size)),
+                    (last_vertex - origin + Vector2i::ONE).clamp(Vector2i::ZERO, Vector2i::splat(shape_size)),
+                );
+                if rect.size.x <= 0 || rect.size.y <= 0 {
+                    continue;
+                }
+                Self::read_heights(data, location, &mut region_shape.heights, rect);
+                ps.shape_set_data(region_shape.shape, &Self::shape_data(&region_shape.heights, shape_size).to_variant());
+            }
+        }
+    }
+
+    fn region_transform(data: &FastTerrainData, region_loc: Vector2i) -> Transform3D {
+        // HeightMapShape3D is centered on its origin
+        let region_size = data.get_region_size() as f32;
+        let spacing = data.get_vertex_spacing();
+        let center = (Vector2::new(region_loc.x as f32, region_loc.y as f32) + Vector2::splat(0.5)) * region_size;
+        let mut transform = Transform3D::IDENTITY.scaled(Vector3::new(spacing, 1.0, spacing));
+        transform.origin = Vector3::new(center.x, 0.0, center.y) * spacing;
+        transform
+    }
+
+    // Rereads the heights inside rect, in shape coordinates, from the height and control
+    // maps. Only the pixels inside rect are copied out of each map, as raw floats
+    fn read_heights(data: &FastTerrainData, region_loc: Vector2i, heights: &mut [f32], rect: Rect2i) {
+        let region_size = data.get_region_size();
+        let shape_size = region_size + 1;
+
+        let maps = |location: Vector2i, pixels: Rect2i| {
+            if pixels.size.x <= 0 || pixels.size.y <= 0 {
+                return None;
+            }
+            let region = data.get_region(location).filter(|_| data.has_region(location))?;
+            let region = region.bind();
+            let height_map = region.get_map(MapType::Height)?;
+            let control_map = region.get_map(MapType::Control)?;
+            if height_map.get_format() != Format::RF || control_map.get_format() != Format::RF {
+                godot_error!("Region {} maps are not RF. Cannot build collision", location);
+                return None;
+            }
+            // A full read takes the map as is, an edit only copies the rect it touched
+            let read_rect = |map: &Gd<Image>| {
+                if pixels.size == map.get_size() {
+                    Some(map.get_data())
+                } else {
+                    map.get_region(pixels).map(|image| image.get_data())
+                }
+            };
+            Some((pixels, read_rect(&height_map)?, read_rect(&control_map)?))
+        };
+        // One extra row and column borrowed from the neighbours closes the gaps between regions.
+        // Without a neighbour the outer edge repeats the region's own border, which own covers
+        let own_start = rect.position.clamp(Vector2i::ZERO, Vector2i::splat(region_size - 1));
+        let own_end = rect.end().clamp(Vector2i::ZERO, Vector2i::splat(region_size));
+        let own = maps(region_loc, Rect2i::from_corners(own_start, own_end));
+        let reaches_right = rect.end().x > region_size;
+        let reaches_below = rect.end().y > region_size;
+        let right = if reaches_right {
+            let pixels = Rect2i::from_corners(Vector2i::new(0, rect.position.y), Vector2i::new(1, own_end.y));
+            maps(region_loc + Vector2i::new(1, 0), pixels)
+        } else {
+            None
+        };
+        let below = if reaches_below {
+            let pixels = Rect2i::from_corners(Vector2i::new(rect.position.x, 0), Vector2i::new(own_end.x, 1));
+            maps(region_loc + Vector2i::new(0, 1), pixels)
+        } else {
+            None
+        };
+        let corner = if reaches_right && reaches_below {
+            maps(region_loc + Vector2i::new(1, 1), Rect2i::new(Vector2i::ZERO, Vector2i::ONE))
+        } else {
+            None
+        };
+
+        let read = |pixels: &Rect2i, bytes: &PackedByteArray, pixel: Vector2i| {
+            let local = pixel - pixels.position;
+            let offset = (local.y * pixels.size.x + local.x) as usize * 4;
+            bytes
+                .as_slice()
+                .get(offset..offset + 4)
+                .map(|value| f32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+        };
+
+        for z in rect.position.y..rect.end().y {
+            for x in rect.position.x..rect.end().x {
+                let (source, pixel) = match (x == region_size, z == region_size) {
+                    (false, false) => (&own, Vector2i::new(x, z)),
+                    (true, false) => (&right, Vector2i::new(0, z)),
+                    (false, true) => (&below, Vector2i::new(x, 0)),
+                    (true, true) => (&corner, Vector2i::ZERO),
+                };
+                // Without a neighbour the outer edge repeats the region's own border
+                let (source, pixel) = match source {
+                    Some(_) => (source, pixel),
+                    None => (&own, Vector2i::new(x.min(region_size - 1), z.min(region_size - 1))),
+                };
+
+                let height = match source {
+                    Some((pixels, height_map, control_map)) => {
+                        let control = read(pixels, control_map, pixel).map(ControlPixel::from_f32).unwrap_or_default();
+                        if control.is_hole() {
+                            // NaN heights leave a gap in the shape
+                            f32::NAN
+                        } else {
+                            read(pixels, height_map, pixel).unwrap_or(0.0)
+                        }
+                    }
+                    None => 0.0,
+                };
+                heights[(z * shape_size + x) as usize] = height;
+            }
+        }
+    }
+
+    fn shape_data(heights: &[f32], shape_size: i32) -> Dictionary {
+        let (mut min_height, mut max_height) = heights
+            .iter()
+            .filter(|height| !height.is_nan())
+            .fold((f32::MAX, f32::MIN), |(min, max), height| (min.min(*height), max.max(*height)));
+        if min_height > max_height {
+            min_height = 0.0;
+            max_height = 0.0;
+        }
+
+        let mut shape_data = Dictionary::new();
+        shape_data.set("width", shape_size);
+        shape_data.set("depth", shape_size);
+        shape_data.set("heights", PackedFloat32Array::from(heights));
+        shape_data.set("min_height", min_height);
+        shape_data.set("max_height", max_height);
+        shape_data
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+
+use godot::{
+    classes::{resource_loader::CacheMode, DirAccess, FileAccess, Image, ResourceLoader},
+    global::Error,
+    prelude::*,
+};
+
+use crate::{
+    control_pixel::ControlPixel,
+    fast_terrain_region::{FastTerrainRegion, MapType},
+    fast_terrain_util::FastTerrainUtil,
+    generated_texture::GeneratedTexture,
+};
+
+#[derive(GodotClass)]
+#[class(tool, base=RefCounted)]
+pub struct FastTerrainData {
+    #[base]
+    base: Base<RefCounted>,
+
+    region_size: i32,
+    vertex_spacing: f32,
+
+    // All loaded regions, including those marked deleted until the next save
+    regions: HashMap<Vector2i, Gd<FastTerrainRegion>>,
+    // Active (non-deleted) region locations in the order they were added
+    region_locations: Vec<Vector2i>,
+
+    // Region maps as Texture2DArrays, one layer per active region in region_locations order
+    generated_height_maps: Gd<GeneratedTexture>,
+    generated_control_maps: Gd<GeneratedTexture>,
+    generated_color_maps: Gd<GeneratedTexture>,
+    // Layer + 1 of the region at each cell of the REGION_MAP_SIZE grid, 0 where there is
+    // none. Row major from the lowest location, so shaders find a layer from world XZ
+    region_map: PackedInt32Array,
+    // Region pixel rects painted since the last flush, whose mipmaps are stale
+    pending_mipmaps: HashMap<(MapType, Vector2i), Rect2i>,
+}
+
+#[godot_api]
+impl IRefCounted for FastTerrainData {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            base,
+            region_size: 256,
+            vertex_spacing: 1.0,
+            regions: HashMap::new(),
+            region_locations: Vec::new(),
+            generated_height_maps: GeneratedTexture::new(),
+            generated_control_maps: GeneratedTexture::new(),
+            generated_color_maps: GeneratedTexture::new(),
+            region_map: PackedInt32Array::new(),
+            pending_mipmaps: HashMap::new(),
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainData {
+    pub const CURRENT_VERSION: f32 = 0.94;
+    pub const REGION_MAP_SIZE: i32 = 32;
+
+    #[signal]
+    fn region_map_changed();
+    #[signal]
+    fn maps_edited(edited_area: Aabb);
+    // Pixel rect in global vertex coordinates, for partial texture uploads
+    #[signal]
+    fn map_rect_changed(map_type: i32, changed_rect: Rect2i);
+
+    pub fn initialize(&mut self, region_size: i32, vertex_spacing: f32) {
+        godot_print!(
+            "Initializing data with region size: {} vertex spacing: {}",
+            region_size,
+            vertex_spacing
+        );
+        self.region_size = region_size;
+        self.vertex_spacing = vertex_spacing;
+    }
+
+    // Called after map pixels change so collision and other consumers can refresh the area
+    #[func]
+    pub fn add_edited_area(&mut self, edited_area: Aabb) {
+        self.base_mut().emit_signal("maps_edited", &[edited_area.to_variant()]);
+    }
+
+    // Called as pixels are painted. Queues the touched region layers for the next
+    // flush_maps, which updates their mipmaps under the rect and uploads them whole, and
+    // lets other renderers react to the rect
+    #[func]
+    pub fn add_changed_rect(&mut self, map_type: i32, changed_rect: Rect2i) {
+        let generated = match map_type {
+            t if t == MapType::Height as i32 => Some((MapType::Height, self.generated_height_maps.clone())),
+            t if t == MapType::Control as i32 => Some((MapType::Control, self.generated_control_maps.clone())),
+            t if t == MapType::Color as i32 => Some((MapType::Color, self.generated_color_maps.clone())),
+            _ => None,
+        };
+        if let Some((map_type, mut generated)) = generated {
+            let mut generated = generated.bind_mut();
+            for (layer, region_loc) in self.region_locations.iter().enumerate() {
+                let region_rect = Rect2i::new(*region_loc * self.region_size, Vector2i::splat(self.region_size));
+                if let Some(rect) = region_rect.intersection(changed_rect) {
+                    let rect = Rect2i::new(rect.position - region_rect.position, rect.size);
+                    self.pending_mipmaps
+                        .entry((map_type, *region_loc))
+                        .and_modify(|pending| *pending = pending.merge(rect))
+                        .or_insert(rect);
+                    generated.add_dirty_layer(layer as i32);
+                }
+            }
+        }
+
+        self.base_mut()
+            .emit_signal("map_rect_changed", &[map_type.to_variant(), changed_rect.to_variant()]);
+    }
+
+    // Syncs the generated map arrays with the active regions. The arrays are only rebuilt
+    // when the region count changes. Otherwise swapped layers upload on the next flush_maps
+    #[func]
+    pub fn update_maps(&mut self) {
+        let regions = self.get_regions_active();
+        let maps = |map_type: MapType| -> Array<Gd<Image>> {
+            regions
+                .iter_shared()
+                .filter_map(|region| region.bind().get_map(map_type))
+                .collect()
+        };
+        let (height_maps, control_maps, color_maps) =
+            (maps(MapType::Height), maps(MapType::Control), maps(MapType::Color));
+        if height_maps.len() != regions.len() || control_maps.len() != regions.len() || color_maps.len() != regions.len() {
+            godot_error!("Some regions are missing maps. Cannot update generated maps");
+            return;
+        }
+
+        self.generated_height_maps.bind_mut().set_layers(height_maps);
+        self.generated_control_maps.bind_mut().set_layers(control_maps);
+        self.generated_color_maps.bind_mut().set_layers(color_maps);
+
+        let mut region_map = PackedInt32Array::new();
+        region_map.resize((Self::REGION_MAP_SIZE * Self::REGION_MAP_SIZE) as usize);
+        for (layer, region_loc) in self.region_locations.iter().enumerate() {
+            let index = Self::get_region_map_index(*region_loc);
+            if index >= 0 {
+                region_map[index as usize] = layer as i32 + 1;
+            }
+        }
+        self.region_map = region_map;
+        godot_print!("Updated generated maps with {} regions", self.region_locations.len());
+    }
+
+    // Uploads the layers painted since the last call. Call once per frame
+    #[func]
+    pub fn flush_maps(&mut self) -> i32 {
+        self.update_mipmaps();
+        self.generated_height_maps.bind_mut().flush()
+            + self.generated_control_maps.bind_mut().flush()
+            + self.generated_color_maps.bind_mut().flush()
+    }
+
+    #[func]
+    pub fn get_height_maps_rid(&self) -> Rid {
+        self.generated_height_maps.bind().get_rid()
+    }
+
+    #[func]
+    pub fn get_control_maps_rid(&self) -> Rid {
+        self.generated_control_maps.bind().get_rid()
+    }
+
+    #[func]
+    pub fn get_color_maps_rid(&self) -> Rid {
+        self.generated_color_maps.bind().get_rid()
+    }
+
+    #[func]
+    pub fn get_region_map(&self) -> PackedInt32Array {
+        self.region_map.clone()
+    }
+
+    // Index of a location in the region map, or -1 when out of bounds
+    #[func]
+    pub fn get_region_map_index(region_loc: Vector2i) -> i32 {
+        let half = Self::REGION_MAP_SIZE / 2;
+        let cell = region_loc + Vector2i::splat(half);
+        if cell.x < 0 || cell.y < 0 || cell.x >= Self::REGION_MAP_SIZE || cell.y >= Self::REGION_MAP_SIZE {
+            return -1;
+        }
+        cell.y * Self::REGION_MAP_SIZE + cell.x
+    }
+
+    // Texture array layer of the region at a location, or -1 when there is none
+    #[func]
+    pub fn get_region_id(&self, region_loc: Vector2i) -> i32 {
+        let index = Self::get_region_map_index(region_loc);
+        if index < 0 {
+            return -1;
+        }
+        self.region_map.get(index as usize).map_or(-1, |layer| layer - 1)
+    }
+
+    #[func]
+    pub fn get_region_idp(&self, global_position: Vector3) -> i32 {
+        self.get_region_id(self.get_region_location(global_position))
+    }
+
+    #[func]
+    pub fn get_region_size(&self) -> i32 {
+        self.region_size
+    }
+
+    #[func]
+    pub fn set_vertex_spacing(&mut self, spacing: f32) {
+        self.vertex_spacing = spacing.clamp(0.25, 100.0);
+        godot_print!("Setting vertex spacing: {}", self.vertex_spacing);
+        for region in self.regions.values_mut() {
+            region.bind_mut().set_vertex_spacing(self.vertex_spacing);
+        }
+    }
+
+    #[func]
+    pub fn get_vertex_spacing(&self) -> f32 {
+        self.vertex_spacing
+    }
+
+    #[func]
+    pub fn get_region_count(&self) -> i32 {
+        self.region_locations.len() as i32
+    }
+
+    #[func]
+    pub fn get_region_locations(&self) -> Array<Vector2i> {
+        self.region_locations.iter().copied().collect()
+    }
+
+    #[func]
+    pub fn get_regions_active(&self) -> Array<Gd<FastTerrainRegion>> {
+        self.region_locations
+            .iter()
+            .filter_map(|location| self.regions.get(location).cloned())
+            .collect()
+    }
+
+    #[func]
+    pub fn get_height_range(&self) -> Vector2 {
+        let mut range = Vector2::ZERO;
+        for (i, region) in self.get_regions_active().iter_shared().enumerate() {
+            let region_range = region.bind().get_height_range();
+            if i == 0 {
+                range = region_range;
+            } else {
+                range.x = range.x.min(region_range.x);
+                range.y = range.y.max(region_range.y);
+            }
+        }
+        range
+    }
+
+    #[func]
+    pub fn get_region_location(&self, global_position: Vector3) -> Vector2i {
+        let region_width = self.region_size as f32 * self.vertex_spacing;
+        Vector2i::new(
+            (global_position.x / region_width).floor() as i32,
+            (global_position.z / region_width).floor() as i32,
+        )
+    }
+
+    #[func]
+    pub fn is_in_bounds(&self, region_loc: Vector2i) -> bool {
+        let half = Self::REGION_MAP_SIZE / 2;
+        region_loc.x >= -half && region_loc.x < half && region_loc.y >= -half && region_loc.y < half
+    }
+
+    #[func]
+    pub fn has_region(&self, region_loc: Vector2i) -> bool {
+        self.region_locations.contains(&region_loc)
+    }
+
+    #[func]
+    pub fn has_regionp(&self, global_position: Vector3) -> bool {
+        self.has_region(self.get_region_location(global_position))
+    }
+
+    #[func]
+    pub fn get_region(&self, region_loc: Vector2i) -> Option<Gd<FastTerrainRegion>> {
+        self.regions.get(&region_loc).cloned()
+    }
+
+    #[func]
+    pub fn get_regionp(&self, global_position: Vector3) -> Option<Gd<FastTerrainRegion>> {
+        let region_loc = self.get_region_location(global_position);
+        if self.has_region(region_loc) {
+            self.get_region(region_loc)
+        } else {
+            None
+        }
+    }
+
+    #[func]
+    pub fn add_region(&mut self, region: Option<Gd<FastTerrainRegion>>, update: bool) -> Error {
+        let Some(mut region) = region else {
+            godot_error!("Provided region is null. Returning");
+            return Error::ERR_INVALID_PARAMETER;
+        };
+
+        let region_loc = region.bind().get_location();
+        if !self.is_in_bounds(region_loc) {
+            godot_error!(
+                "Location {} out of bounds. Max: {} to {}",
+                region_loc,
+                -Self::REGION_MAP_SIZE / 2,
+                Self::REGION_MAP_SIZE / 2 - 1
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+
+        let region_size = region.bind().get_region_size();
+        if region_size != self.region_size {
+            godot_error!(
+                "Region {} size {} doesn't match terrain region size {}",
+                region_loc,
+                region_size,
+                self.region_size
+            );
+            return Error::ERR_INVALID_DATA;
+        }
+
+        godot_print!("Adding region at location: {}, update maps: {}", region_loc, update);
+        {
+            let mut region = region.bind_mut();
+            region.set_vertex_spacing(self.vertex_spacing);
+            region.set_deleted(false);
+        }
+        self.regions.insert(region_loc, region);
+        if !self.region_locations.contains(&region_loc) {
+            self.region_locations.push(region_loc);
+        }
+
+        if update {
+            self.update_maps();
+            self.base_mut().emit_signal("region_map_changed", &[]);
+        }
+        Error::OK
+    }
+
+    #[func]
+    pub fn add_region_blank(&mut self, region_loc: Vector2i, update: bool) -> Option<Gd<FastTerrainRegion>> {
+        if self.has_region(region_loc) {
+            godot_error!("Region {} already exists", region_loc);
+            return None;
+        }
+
+        let mut region = FastTerrainRegion::new_gd();
+        {
+            let mut region = region.bind_mut();
+            region.set_location(region_loc);
+            region.set_region_size(self.region_size);
+            region.set_modified(true);
+        }
+
+        if self.add_region(Some(region.clone()), update) == Error::OK {
+            Some(region)
+        } else {
+            None
+        }
+    }
+
+    #[func]
+    pub fn add_region_blankp(&mut self, global_position: Vector3, update: bool) -> Option<Gd<FastTerrainRegion>> {
+        let region_loc = self.get_region_location(global_position);
+        self.add_region_blank(region_loc, update)
+    }
+
+    #[func]
+    pub fn remove_region(&mut self, region: Option<Gd<FastTerrainRegion>>, update: bool) {
+        let Some(mut region) = region else {
+            godot_error!("Provided region is null. Returning");
+            return;
+        };
+
+        let region_loc = region.bind().get_location();
+        let Some(index) = self.region_locations.iter().position(|loc| *loc == region_loc) else {
+            godot_error!("Region {} not found in region locations", region_loc);
+            return;
+        };
+
+        godot_print!("Removing region at: {}", region_loc);
+        self.region_locations.remove(index);
+        // Keep the region so the next save can delete its file
+        {
+            let mut region = region.bind_mut();
+            region.set_deleted(true);
+            region.set_modified(true);
+        }
+
+        if update {
+            self.update_maps();
+            self.base_mut().emit_signal("region_map_changed", &[]);
+        }
+    }
+
+    #[func]
+    pub fn remove_regionp(&mut self, global_position: Vector3, update: bool) {
+        let region = self.get_regionp(global_position);
+        self.remove_region(region, update);
+    }
+
+    // None outside of regions. Missing data is never signalled in the color itself, as any
+    // bit pattern, NaN included, is a valid control value
+    pub fn get_pixel(&self, map_type: MapType, global_position: Vector3) -> Option<Color> {
+        let region = self.get_regionp(global_position)?;
+        let region = region.bind();
+        if region.is_deleted() {
+            return None;
+        }
+        let map = region.get_map(map_type)?;
+
+        let region_loc = region.get_location();
+        let descaled = global_position / self.vertex_spacing;
+        let img_pos = Vector2i::new(
+            (descaled.x - (region_loc.x * self.region_size) as f32).floor() as i32,
+            (descaled.z - (region_loc.y * self.region_size) as f32).floor() as i32,
+        );
+        let img_pos = img_pos.clamp(Vector2i::ZERO, Vector2i::splat(self.region_size - 1));
+        Some(map.get_pixelv(img_pos))
+    }
+
+    fn get_height_pixel(&self, global_position: Vector3) -> f32 {
+        self.get_pixel(MapType::Height, global_position).map_or(f32::NAN, |pixel| pixel.r)
+    }
+
+    #[func]
+    pub fn get_control(&self, global_position: Vector3) -> ControlPixel {
+        self.get_pixel(MapType::Control, global_position)
+            .map(|pixel| ControlPixel::from_f32(pixel.r))
+            .unwrap_or_default()
+    }
+
+    #[func]
+    pub fn get_height(&self, global_position: Vector3) -> f32 {
+        if !self.has_regionp(global_position) || self.get_control(global_position).is_hole() {
+            return f32::NAN;
+        }
+
+        let step = self.vertex_spacing;
+        let pos = Vector3::new(global_position.x, 0.0, global_position.z);
+        let pos_round = pos.snapped(Vector3::new(step, 0.0, step));
+
+        // Return the vertex height directly if we're on it
+        if (pos - pos_round).length() < 0.01 {
+            return self.get_height_pixel(pos);
+        }
+
+        // Otherwise interpolate the four surrounding vertices
+        let pos00 = Vector3::new((pos.x / step).floor() * step, 0.0, (pos.z / step).floor() * step);
+        let pos01 = pos00 + Vector3::new(0.0, 0.0, step);
+        let pos10 = pos00 + Vector3::new(step, 0.0, 0.0);
+        let pos11 = pos00 + Vector3::new(step, 0.0, step);
+        let ht00 = self.get_height_pixel(pos00);
+        let ht01 = self.get_height_pixel(pos01);
+        let ht10 = self.get_height_pixel(pos10);
+        let ht11 = self.get_height_pixel(pos11);
+
+        FastTerrainUtil::bilerp(
+            ht00,
+            ht01,
+            ht10,
+            ht11,
+            Vector2::new(pos00.x, pos00.z),
+            Vector2::new(pos11.x, pos11.z),
+            Vector2::new(pos.x, pos.z),
+        )
+    }
+
+    #[func]
+    pub fn get_normal(&self, global_position: Vector3) -> Vector3 {
+        let height = self.get_height(global_position);
+        if height.is_nan() {
+            return Vector3::new(f32::NAN, f32::NAN, f32::NAN);
+        }
+
+        let step = self.vertex_spacing;
+        let u = height - self.get_height(global_position + Vector3::new(step, 0.0, 0.0));
+        let v = height - self.get_height(global_position + Vector3::new(0.0, 0.0, step));
+        Vector3::new(u, step, v).normalized()
+    }
+
+    #[func]
+    pub fn load_directory(&mut self, directory: GString) {
+        if directory.is_empty() {
+            godot_error!("Specified directory name is blank");
+            return;
+        }
+
+        let Some(mut dir) = DirAccess::open(&directory) else {
+            godot_error!("Cannot open directory: {}", directory);
+            return;
+        };
+
+        godot_print!("Loading region files from {}", directory);
+        self.regions.clear();
+        self.region_locations.clear();
+
+        let files = dir.get_files();
+
+        for fname in files.as_slice() {
+            if !fname.begins_with("terrain3d") || !fname.ends_with(".res") {
+                continue;
+            }
+            let region_loc = FastTerrainUtil::filename_to_location(fname.clone());
+            if region_loc.x == i32::MAX {
+                godot_error!("Cannot get region location from file name: {}", fname);
+                continue;
+            }
+            self.load_region(region_loc, directory.clone(), false);
+        }
+
+        self.update_maps();
+        self.base_mut().emit_signal("region_map_changed", &[]);
+    }
+
+    #[func]
+    pub fn load_region(&mut self, region_loc: Vector2i, directory: GString, update: bool) -> Error {
+        let path = Self::region_path(&directory, region_loc);
+        if !FileAccess::file_exists(&path) {
+            godot_error!("File {} doesn't exist", path);
+            return Error::ERR_DOES_NOT_EXIST;
+        }
+
+        godot_print!("Loading region from {}", path);
+        let resource = ResourceLoader::singleton()
+            .load_ex(&path)
+            .cache_mode(CacheMode::IGNORE)
+            .done();
+        let Some(Ok(mut region)) = resource.map(|res| res.try_cast::<FastTerrainRegion>()) else {
+            godot_error!("Cannot load region at {}", path);
+            return Error::ERR_CANT_OPEN;
+        };
+
+        {
+            let mut region = region.bind_mut();
+            region.set_location(region_loc);
+            region.set_modified(false);
+            region.upgrade();
+        }
+        self.add_region(Some(region), update)
+    }
+
+    #[func]
+    pub fn save_directory(&mut self, directory: GString, sixteen_bit: bool) -> Error {
+        if directory.is_empty() {
+            godot_error!("Specified directory name is blank");
+            return Error::ERR_FILE_BAD_PATH;
+        }
+
+        if !DirAccess::dir_exists_absolute(&directory) {
+            let err = DirAccess::make_dir_recursive_absolute(&directory);
+            if err != Error::OK {
+                godot_error!("Cannot create directory: {}. Error code: {:?}", directory, err);
+                return err;
+            }
+        }
+
+        godot_print!("Saving data files to {}", directory);
+        let locations: Vec<Vector2i> = self.regions.keys().copied().collect();
+        let mut result = Error::OK;
+        for region_loc in locations {
+            let err = self.save_region(region_loc, directory.clone(), sixteen_bit);
+            if err != Error::OK && err != Error::ERR_SKIP {
+                result = err;
+            }
+        }
+        result
+    }
+
+    #[func]
+    pub fn save_region(&mut self, region_loc: Vector2i, directory: GString, sixteen_bit: bool) -> Error {
+        let Some(mut region) = self.regions.get(&region_loc).cloned() else {
+            godot_error!("No region found at: {}", region_loc);
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+
+        let path = Self::region_path(&directory, region_loc);
+        if region.bind().is_deleted() {
+            godot_print!("Removing deleted region {} file {}", region_loc, path);
+            self.regions.remove(&region_loc);
+            if FileAccess::file_exists(&path) {
+                let err = DirAccess::remove_absolute(&path);
+                if err != Error::OK {
+                    godot_error!("Cannot remove file: {}. Error code: {:?}", path, err);
+                    return err;
+                }
+            }
+            return Error::OK;
+        }
+
+        self.update_mipmaps();
+        let result = region.bind_mut().save(path, sixteen_bit);
+        result
+    }
+
+    // Rebuilds the mipmaps under the rects painted since the last call, once per map
+    // however many dabs or undo tiles touched it
+    fn update_mipmaps(&mut self) {
+        for ((map_type, region_loc), rect) in std::mem::take(&mut self.pending_mipmaps) {
+            let map = self
+                .regions
+                .get(&region_loc)
+                .and_then(|region| region.bind().get_map(map_type));
+            if let Some(map) = map.filter(|map| map.has_mipmaps()) {
+                FastTerrainUtil::update_mipmaps(map, rect);
+            }
+        }
+    }
+
+    fn region_path(directory: &GString, region_loc: Vector2i) -> GString {
+        directory.path_join(&FastTerrainUtil::location_to_filename(region_loc))
+    }
+}
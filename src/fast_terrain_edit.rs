@@ -0,0 +1,363 @@
+use std::collections::HashSet;
+
+use godot::{classes::Image, prelude::*};
+
+use crate::{
+    fast_terrain_data::FastTerrainData,
+    fast_terrain_instancer::FastTerrainInstancer,
+    fast_terrain_region::{FastTerrainRegion, MapType},
+};
+
+// Maps are captured in square tiles of this many pixels, each the first time it is touched
+const TILE_SIZE: i32 = 64;
+
+// Before and after pixels of one tile of one region map
+struct TileDiff {
+    region_loc: Vector2i,
+    map_type: MapType,
+    rect: Rect2i,
+    before: Gd<Image>,
+    after: Option<Gd<Image>>,
+}
+
+// One instance cell of one mesh id before and after the edit. None is a missing cell
+struct CellDiff {
+    region_loc: Vector2i,
+    mesh_id: i32,
+    cell: Vector2i,
+    before: Option<VariantArray>,
+    after: Option<VariantArray>,
+}
+
+enum RegionChange {
+    Added(Gd<FastTerrainRegion>),
+    Removed(Gd<FastTerrainRegion>),
+}
+
+// One undoable edit transaction. Only the touched tiles of each map and the touched instance
+// cells are kept, never whole regions. Hook undo and redo into EditorUndoRedoManager, or push it to a FastTerrainHistory
+#[derive(GodotClass)]
+#[class(tool, base=RefCounted)]
+pub struct FastTerrainEdit {
+    #[base]
+    base: Base<RefCounted>,
+
+    #[var]
+    data: Option<Gd<FastTerrainData>>,
+    tiles: Vec<TileDiff>,
+    captured_tiles: HashSet<(Vector2i, MapType, Vector2i)>,
+    cells: Vec<CellDiff>,
+    captured_cells: HashSet<(Vector2i, i32, Vector2i)>,
+    instancer: Option<Gd<FastTerrainInstancer>>,
+    regions: Vec<RegionChange>,
+    finished: bool,
+}
+
+#[godot_api]
+impl IRefCounted for FastTerrainEdit {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            base,
+            data: None,
+            tiles: Vec::new(),
+            captured_tiles: HashSet::new(),
+            cells: Vec::new(),
+            captured_cells: HashSet::new(),
+            instancer: None,
+            regions: Vec::new(),
+            finished: false,
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainEdit {
+    #[func]
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.cells.is_empty() && self.regions.is_empty()
+    }
+
+    // Bytes held by the before and after tiles
+    #[func]
+    pub fn get_memory_usage(&self) -> i64 {
+        self.tiles
+            .iter()
+            .flat_map(|tile| std::iter::once(&tile.before).chain(tile.after.as_ref()))
+            .map(|image| image.get_data().len() as i64)
+            .sum()
+    }
+
+    #[func]
+    pub fn add_region_blank(&mut self, region_loc: Vector2i) -> Option<Gd<FastTerrainRegion>> {
+        let mut data = self.data.clone()?;
+        let region = data.bind_mut().add_region_blank(region_loc, true)?;
+        self.regions.push(RegionChange::Added(region.clone()));
+        Some(region)
+    }
+
+    #[func]
+    pub fn remove_region(&mut self, region_loc: Vector2i) {
+        let Some(mut data) = self.data.clone() else {
+            return;
+        };
+        if !data.bind().has_region(region_loc) {
+            godot_error!("No region at {} to remove", region_loc);
+            return;
+        }
+        let region = data.bind().get_region(region_loc);
+        if let Some(region) = region {
+            data.bind_mut().remove_region(Some(region.clone()), true);
+            self.regions.push(RegionChange::Removed(region));
+        }
+    }
+
+    // Captures the after state of every tile. Called once the last pixel is written
+    #[func]
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+
+        let data = data.bind();
+        for tile in &mut self.tiles {
+            tile.after = Self::get_map(&data, tile.region_loc, tile.map_type).and_then(|map| map.get_region(tile.rect));
+        }
+        // Cells captured but left unchanged are dropped
+        self.cells.retain_mut(|diff| {
+            diff.after = data
+                .get_region(diff.region_loc)
+                .and_then(|region| Self::get_cell(&region.bind(), diff.mesh_id, diff.cell));
+            diff.after != diff.before
+        });
+        self.finished = true;
+    }
+
+    #[func]
+    pub fn undo(&mut self) {
+        self.finish();
+        godot_print!("Undoing edit: {} tiles, {} region changes", self.tiles.len(), self.regions.len());
+        let Some(mut data) = self.data.clone() else {
+            return;
+        };
+
+        // Reverse order of redo, so painted tiles are restored before their region is removed
+        Self::apply_tiles(&mut data, self.tiles.iter().rev().map(|tile| (tile, &tile.before)));
+        self.apply_cells(&data, self.cells.iter().rev().map(|diff| (diff, diff.before.as_ref())));
+        for change in self.regions.iter().rev() {
+            match change {
+                RegionChange::Added(region) => data.bind_mut().remove_region(Some(region.clone()), true),
+                RegionChange::Removed(region) => {
+                    data.bind_mut().add_region(Some(region.clone()), true);
+                }
+            }
+        }
+    }
+
+    #[func]
+    pub fn redo(&mut self) {
+        self.finish();
+        godot_print!("Redoing edit: {} tiles, {} region changes", self.tiles.len(), self.regions.len());
+        let Some(mut data) = self.data.clone() else {
+            return;
+        };
+
+        for change in self.regions.iter() {
+            match change {
+                RegionChange::Added(region) => {
+                    data.bind_mut().add_region(Some(region.clone()), true);
+                }
+                RegionChange::Removed(region) => data.bind_mut().remove_region(Some(region.clone()), true),
+            }
+        }
+        Self::apply_tiles(
+            &mut data,
+            self.tiles.iter().filter_map(|tile| tile.after.as_ref().map(|after| (tile, after))),
+        );
+        self.apply_cells(&data, self.cells.iter().map(|diff| (diff, diff.after.as_ref())));
+    }
+}
+
+impl FastTerrainEdit {
+    pub fn begin(data: Gd<FastTerrainData>) -> Gd<Self> {
+        let mut edit = Self::new_gd();
+        edit.bind_mut().data = Some(data);
+        edit
+    }
+
+    // Records the current pixels of rect, in region pixel coordinates, before they are
+    // written. Only tiles not captured yet are read from the map
+    pub fn capture(&mut self, region_loc: Vector2i, map_type: MapType, rect: Rect2i) {
+        if self.finished {
+            godot_error!("Edit already finished. Cannot capture more tiles");
+            return;
+        }
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+        let Some(map) = Self::get_map(&data.bind(), region_loc, map_type) else {
+            return;
+        };
+
+        let map_rect = Rect2i::new(Vector2i::ZERO, map.get_size());
+        let first = rect.position / TILE_SIZE;
+        let last = (rect.end() - Vector2i::ONE) / TILE_SIZE;
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let tile = Vector2i::new(x, y);
+                if !self.captured_tiles.insert((region_loc, map_type, tile)) {
+                    continue;
+                }
+                let Some(tile_rect) = Rect2i::new(tile * TILE_SIZE, Vector2i::splat(TILE_SIZE)).intersection(map_rect) else {
+                    continue;
+                };
+                if let Some(before) = map.get_region(tile_rect) {
+                    self.tiles.push(TileDiff {
+                        region_loc,
+                        map_type,
+                        rect: tile_rect,
+                        before,
+                        after: None,
+                    });
+                }
+            }
+        }
+    }
+
+    // Records the instance cells a brush of radius at global_position can change, for one
+    // mesh id or all of them when mesh_id is negative. The instancer redraws the cells on
+    // undo and redo
+    pub fn capture_instances(&mut self, instancer: Gd<FastTerrainInstancer>, global_position: Vector3, radius: f32, mesh_id: i32) {
+        if self.finished {
+            godot_error!("Edit already finished. Cannot capture more instances");
+            return;
+        }
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+        let data = data.bind();
+
+        for region_loc in FastTerrainInstancer::regions_in_radius(&data, global_position, radius) {
+            let Some(region) = data.get_region(region_loc) else {
+                continue;
+            };
+            let region = region.bind();
+            let mesh_ids: Vec<i32> = if mesh_id >= 0 {
+                vec![mesh_id]
+            } else {
+                // Thinning every mesh id only changes the ones already there
+                region.get_instances().keys_array().iter_shared().filter_map(|id| id.try_to::<i32>().ok()).collect()
+            };
+            for cell in FastTerrainInstancer::cells_in_radius(&data, region_loc, global_position, radius) {
+                for &id in &mesh_ids {
+                    if !self.captured_cells.insert((region_loc, id, cell)) {
+                        continue;
+                    }
+                    self.cells.push(CellDiff {
+                        region_loc,
+                        mesh_id: id,
+                        cell,
+                        before: Self::get_cell(&region, id, cell),
+                        after: None,
+                    });
+                }
+            }
+        }
+        self.instancer = Some(instancer);
+    }
+
+    fn get_cell(region: &FastTerrainRegion, mesh_id: i32, cell: Vector2i) -> Option<VariantArray> {
+        region
+            .get_instances()
+            .get(mesh_id)
+            .and_then(|cells| cells.try_to::<Dictionary>().ok())
+            .and_then(|cells| cells.get(cell))
+            .and_then(|cell| cell.try_to::<VariantArray>().ok())
+            .map(|cell| cell.duplicate_deep())
+    }
+
+    // Writes each cell, removing missing ones, then redraws the regions they are in
+    fn apply_cells<'a>(&self, data: &Gd<FastTerrainData>, cells: impl Iterator<Item = (&'a CellDiff, Option<&'a VariantArray>)>) {
+        let mut regions = Vec::new();
+        for (diff, cell_data) in cells {
+            let Some(mut region) = data.bind().get_region(diff.region_loc) else {
+                godot_error!("Region {} missing. Cannot restore instances", diff.region_loc);
+                continue;
+            };
+            let mut instances = region.bind().get_instances();
+            let mut cells = instances
+                .get(diff.mesh_id)
+                .and_then(|cells| cells.try_to::<Dictionary>().ok())
+                .unwrap_or_default();
+            match cell_data {
+                Some(cell_data) => cells.set(diff.cell, cell_data.duplicate_deep()),
+                None => {
+                    cells.remove(diff.cell);
+                }
+            }
+            if cells.is_empty() {
+                instances.remove(diff.mesh_id);
+            } else {
+                instances.set(diff.mesh_id, cells);
+            }
+            region.bind_mut().set_modified(true);
+            if !regions.contains(&diff.region_loc) {
+                regions.push(diff.region_loc);
+            }
+        }
+
+        if let Some(mut instancer) = self.instancer.clone() {
+            for region_loc in regions {
+                instancer.bind_mut().update_region(region_loc);
+            }
+        }
+    }
+
+    fn get_map(data: &FastTerrainData, region_loc: Vector2i, map_type: MapType) -> Option<Gd<Image>> {
+        data.get_region(region_loc)?.bind().get_map(map_type)
+    }
+
+    // Writes the pixels of each tile, then updates height ranges and the edited area once
+    fn apply_tiles<'a>(data: &mut Gd<FastTerrainData>, tiles: impl Iterator<Item = (&'a TileDiff, &'a Gd<Image>)>) {
+        let (region_size, spacing) = {
+            let data = data.bind();
+            (data.get_region_size(), data.get_vertex_spacing())
+        };
+        let mut height_regions = Vec::new();
+        let mut edited_rect: Option<Rect2i> = None;
+
+        for (tile, pixels) in tiles {
+            let Some(mut region) = data.bind().get_region(tile.region_loc) else {
+                godot_error!("Region {} missing. Cannot restore tile", tile.region_loc);
+                continue;
+            };
+            let Some(mut map) = region.bind().get_map(tile.map_type) else {
+                continue;
+            };
+
+            // Mipmaps are updated on the next flush, from the changed rect
+            map.blit_rect(pixels, Rect2i::new(Vector2i::ZERO, tile.rect.size), tile.rect.position);
+            region.bind_mut().set_modified(true);
+            if tile.map_type == MapType::Height && !height_regions.contains(&region) {
+                height_regions.push(region);
+            }
+
+            let global_rect = Rect2i::new(tile.region_loc * region_size + tile.rect.position, tile.rect.size);
+            data.bind_mut().add_changed_rect(tile.map_type as i32, global_rect);
+            edited_rect = Some(edited_rect.map_or(global_rect, |rect| rect.merge(global_rect)));
+        }
+
+        for mut region in height_regions {
+            region.bind_mut().calc_height_range();
+        }
+        if let Some(rect) = edited_rect {
+            let edited_area = Aabb::new(
+                Vector3::new(rect.position.x as f32, 0.0, rect.position.y as f32) * spacing,
+                Vector3::new(rect.size.x as f32, 0.0, rect.size.y as f32) * spacing,
+            );
+            data.bind_mut().add_edited_area(edited_area);
+        }
+    }
+}
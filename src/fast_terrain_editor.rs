@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet};
+
+use godot::{
+    classes::{Curve, Image},
+    prelude::*,
+};
+
+use crate::{
+    control_pixel::ControlPixel,
+    fast_terrain_assets::MAX_TEXTURES,
+    fast_terrain_data::FastTerrainData,
+    fast_terrain_edit::FastTerrainEdit,
+    fast_terrain_history::FastTerrainHistory,
+    fast_terrain_instancer::{FastTerrainInstancer, ScatterSettings},
+    fast_terrain_region::{FastTerrainRegion, MapType},
+    FastTerrain,
+};
+
+#[derive(GodotConvert, Var, Export, Clone, Copy, PartialEq, Debug)]
+#[godot(via = GString)]
+pub enum Tool {
+    Raise,
+    Lower,
+    Smooth,
+    Flatten,
+    Slope,
+    PaintBase,
+    PaintOverlay,
+    UvRotation,
+    UvScale,
+    Holes,
+    Navigation,
+    Autoshader,
+    Color,
+    Roughness,
+    Scatter,
+}
+
+impl Tool {
+    fn map_type(self) -> MapType {
+        match self {
+            Tool::Raise | Tool::Lower | Tool::Smooth | Tool::Flatten | Tool::Slope => MapType::Height,
+            Tool::Color | Tool::Roughness => MapType::Color,
+            _ => MapType::Control,
+        }
+    }
+}
+
+// Applies brush strokes to the terrain data. Drive it with start_operation, operate
+// for every brush movement, and stop_operation, from GDScript or an editor plugin
+#[derive(GodotClass)]
+#[class(tool, base=RefCounted)]
+pub struct FastTerrainEditor {
+    #[base]
+    base: Base<RefCounted>,
+
+    #[var]
+    terrain: Option<Gd<FastTerrain>>,
+    #[var]
+    tool: Tool,
+    // Brush diameter in world units
+    #[var]
+    brush_size: f32,
+    // Height added per operate at full weight for Raise and Lower. Blend amount for the rest
+    #[var]
+    strength: f32,
+    // Maps distance from the brush center (0 to 1) to weight. Smoothstep when unset
+    #[var]
+    falloff_curve: Option<Gd<Curve>>,
+    // Alpha channel scales the weight across the brush
+    #[var]
+    brush_image: Option<Gd<Image>>,
+    #[var]
+    flatten_height: f32,
+    #[var]
+    slope_start: Vector3,
+    #[var]
+    slope_end: Vector3,
+    // Texture painted by PaintBase and PaintOverlay
+    #[var]
+    texture_id: i32,
+    #[var]
+    uv_rotation: i32,
+    #[var]
+    uv_scale: i32,
+    // Whether Holes, Navigation and Autoshader set or clear their flag, and whether Scatter
+    // places instances or thins them by strength
+    #[var]
+    flag_enabled: bool,
+    // Albedo tint painted into the color map RGB by the Color tool
+    #[var]
+    color: Color,
+    // Roughness modifier painted into the color map alpha by the Roughness tool
+    #[var]
+    roughness: f32,
+    // Mesh asset placed by Scatter. Thinning with -1 removes every mesh
+    #[var]
+    mesh_id: i32,
+    // Scatter randomizes uniform scale between x and y, spin about up and tilt in degrees
+    #[var]
+    scale_range: Vector2,
+    #[var]
+    random_spin: f32,
+    #[var]
+    random_tilt: f32,
+    // Terrain slope in degrees and height that Scatter places on. Height is ignored unless x < y
+    #[var]
+    slope_range: Vector2,
+    #[var]
+    height_range: Vector2,
+    // Same seed and brush path give the same instances
+    #[var]
+    seed: i64,
+    // Finished edits are pushed here when set. Editor plugins can instead take
+    // get_last_edit and register its undo and redo with EditorUndoRedoManager
+    #[var]
+    history: Option<Gd<FastTerrainHistory>>,
+
+    operating: bool,
+    edit: Option<Gd<FastTerrainEdit>>,
+    last_edit: Option<Gd<FastTerrainEdit>>,
+    edited_area: Option<Aabb>,
+    edited_regions: HashSet<Vector2i>,
+}
+
+#[godot_api]
+impl IRefCounted for FastTerrainEditor {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            base,
+            terrain: None,
+            tool: Tool::Raise,
+            brush_size: 10.0,
+            strength: 0.5,
+            falloff_curve: None,
+            brush_image: None,
+            flatten_height: 0.0,
+            slope_start: Vector3::ZERO,
+            slope_end: Vector3::ZERO,
+            texture_id: 0,
+            uv_rotation: 0,
+            uv_scale: 0,
+            flag_enabled: true,
+            color: Color::from_rgb(1.0, 1.0, 1.0),
+            roughness: 1.0,
+            mesh_id: 0,
+            scale_range: Vector2::ONE,
+            random_spin: 360.0,
+            random_tilt: 0.0,
+            slope_range: Vector2::new(0.0, 90.0),
+            height_range: Vector2::ZERO,
+            seed: 0,
+            history: None,
+            operating: false,
+            edit: None,
+            last_edit: None,
+            edited_area: None,
+            edited_regions: HashSet::new(),
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainEditor {
+    #[func]
+    pub fn is_operating(&self) -> bool {
+        self.operating
+    }
+
+    // The edit recorded by the last finished operation, if it changed anything
+    #[func]
+    pub fn get_last_edit(&self) -> Option<Gd<FastTerrainEdit>> {
+        self.last_edit.clone()
+    }
+
+    #[func]
+    pub fn start_operation(&mut self, global_position: Vector3) {
+        let Some(data) = self.get_data() else {
+            godot_error!("Terrain or its data is not set. Cannot start operation");
+            return;
+        };
+        if matches!(self.tool, Tool::PaintBase | Tool::PaintOverlay)
+            && !(0..MAX_TEXTURES).contains(&self.texture_id)
+        {
+            godot_error!("Texture id {} out of range 0-{}", self.texture_id, MAX_TEXTURES - 1);
+            return;
+        }
+
+        godot_print!("Starting {:?} operation at {}", self.tool, global_position);
+        self.operating = true;
+        self.edit = Some(FastTerrainEdit::begin(data));
+        self.edited_area = None;
+        self.edited_regions.clear();
+        self.operate(global_position);
+    }
+
+    #[func]
+    pub fn operate(&mut self, global_position: Vector3) {
+        if !self.operating {
+            godot_error!("Operate called before start_operation");
+            return;
+        }
+        if self.tool == Tool::Scatter {
+            self.scatter(global_position);
+            return;
+        }
+        let Some(mut data) = self.get_data() else {
+            return;
+        };
+
+        let map_type = self.tool.map_type();
+        let changes = match map_type {
+            MapType::Height => self.sculpt(&data.bind(), global_position),
+            MapType::Color => self.paint_color(&data.bind(), global_position),
+            _ => self.paint(&data.bind(), global_position),
+        };
+        let changed_rect = self.apply_changes(&data.bind(), map_type, changes);
+        if let Some(changed_rect) = changed_rect {
+            data.bind_mut().add_changed_rect(map_type as i32, changed_rect);
+        }
+    }
+
+    #[func]
+    pub fn stop_operation(&mut self) {
+        if !self.operating {
+            return;
+        }
+
+        godot_print!("Stopping {:?} operation. Edited regions: {}", self.tool, self.edited_regions.len());
+        self.operating = false;
+        let Some(mut data) = self.get_data() else {
+            return;
+        };
+
+        for region_loc in &self.edited_regions {
+            if let Some(mut region) = data.bind().get_region(*region_loc) {
+                region.bind_mut().set_edited(false);
+            }
+        }
+        self.edited_regions.clear();
+
+        if let Some(edited_area) = self.edited_area.take() {
+            data.bind_mut().add_edited_area(edited_area);
+        }
+
+        let Some(mut edit) = self.edit.take() else {
+            return;
+        };
+        edit.bind_mut().finish();
+        if edit.bind().is_empty() {
+            return;
+        }
+        self.last_edit = Some(edit.clone());
+        if let Some(history) = &mut self.history {
+            history.bind_mut().push(edit);
+        }
+    }
+}
+
+impl FastTerrainEditor {
+    fn get_data(&self) -> Option<Gd<FastTerrainData>> {
+        self.terrain.as_ref()?.bind().get_data()
+    }
+
+    fn get_instancer(&self) -> Option<Gd<FastTerrainInstancer>> {
+        self.terrain.as_ref()?.bind().get_instancer()
+    }
+
+    fn scatter(&mut self, global_position: Vector3) {
+        let Some(mut instancer) = self.get_instancer() else {
+            return;
+        };
+        let radius = self.brush_size * 0.5;
+        if let Some(edit) = &mut self.edit {
+            edit.bind_mut().capture_instances(instancer.clone(), global_position, radius, self.mesh_id);
+        }
+
+        let weight = |offset: Vector2| self.falloff(offset.length().min(1.0)) * self.brush_alpha(offset);
+        let mut instancer = instancer.bind_mut();
+        if self.flag_enabled {
+            let settings = ScatterSettings {
+                mesh_id: self.mesh_id,
+                radius,
+                strength: self.strength,
+                scale_range: self.scale_range,
+                random_spin: self.random_spin,
+                random_tilt: self.random_tilt,
+                slope_range: self.slope_range,
+                height_range: self.height_range,
+                seed: self.seed,
+            };
+            instancer.scatter(global_position, &settings, weight);
+        } else {
+            instancer.thin(global_position, radius, self.mesh_id, self.strength, self.seed, weight);
+        }
+    }
+
+    fn falloff(&self, distance: f32) -> f32 {
+        match &self.falloff_curve {
+            Some(curve) => curve.sample(distance).clamp(0.0, 1.0),
+            None => {
+                let t = 1.0 - distance;
+                t * t * (3.0 - 2.0 * t)
+            }
+        }
+    }
+
+    // Brush image alpha at an offset from the brush center, both axes in -1 to 1
+    fn brush_alpha(&self, offset: Vector2) -> f32 {
+        let Some(image) = &self.brush_image else {
+            return 1.0;
+        };
+        let size = image.get_size();
+        let uv = (offset * 0.5 + Vector2::splat(0.5)).clamp(Vector2::ZERO, Vector2::ONE);
+        let x = (uv.x * (size.x - 1) as f32).round() as i32;
+        let y = (uv.y * (size.y - 1) as f32).round() as i32;
+        image.get_pixel(x, y).a
+    }
+
+    // Global vertex coordinates under the brush with their weight
+    fn footprint(&self, spacing: f32, global_position: Vector3) -> Vec<(Vector2i, f32)> {
+        let center = Vector2::new(global_position.x, global_position.z) / spacing;
+        let radius = (self.brush_size * 0.5 / spacing).max(0.5);
+        let min = (center - Vector2::splat(radius)).floor();
+        let max = (center + Vector2::splat(radius)).ceil();
+
+        let mut vertices = Vec::new();
+        for z in min.y as i32..=max.y as i32 {
+            for x in min.x as i32..=max.x as i32 {
+                let offset = (Vector2::new(x as f32, z as f32) - center) / radius;
+                let distance = offset.length();
+                if distance > 1.0 {
+                    continue;
+                }
+                let weight = self.falloff(distance) * self.brush_alpha(offset);
+                if weight > 0.0 {
+                    vertices.push((Vector2i::new(x, z), weight));
+                }
+            }
+        }
+        vertices
+    }
+
+    // New heights keyed by global vertex coordinates. Computed before anything is written
+    // so smoothing reads a consistent snapshot
+    fn sculpt(&self, data: &FastTerrainData, global_position: Vector3) -> Vec<(Vector2i, Color)> {
+        let spacing = data.get_vertex_spacing();
+        let mut maps = MapCache::new(data, MapType::Height);
+        let mut changes = Vec::new();
+
+        for (vertex, weight) in self.footprint(spacing, global_position) {
+            let Some(height) = maps.get_pixel(vertex).map(|pixel| pixel.r) else {
+                continue;
+            };
+
+            let blend = (self.strength * weight).clamp(0.0, 1.0);
+            let new_height = match self.tool {
+                Tool::Raise => height + self.strength * weight,
+                Tool::Lower => height - self.strength * weight,
+                Tool::Smooth => {
+                    let neighbours = [
+                        vertex + Vector2i::new(-1, 0),
+                        vertex + Vector2i::new(1, 0),
+                        vertex + Vector2i::new(0, -1),
+                        vertex + Vector2i::new(0, 1),
+                    ];
+                    let (sum, count) = neighbours
+                        .iter()
+                        .filter_map(|neighbour| maps.get_pixel(*neighbour).map(|pixel| pixel.r))
+                        .fold((height, 1.0), |(sum, count), h| (sum + h, count + 1.0));
+                    lerp(height, sum / count, blend)
+                }
+                Tool::Flatten => lerp(height, self.flatten_height, blend),
+                Tool::Slope => {
+                    let start = Vector2::new(self.slope_start.x, self.slope_start.z);
+                    let direction = Vector2::new(self.slope_end.x, self.slope_end.z) - start;
+                    if direction.length_squared() < f32::EPSILON {
+                        continue;
+                    }
+                    let position = Vector2::new(vertex.x as f32, vertex.y as f32) * spacing;
+                    let t = ((position - start).dot(direction) / direction.length_squared()).clamp(0.0, 1.0);
+                    lerp(height, lerp(self.slope_start.y, self.slope_end.y, t), blend)
+                }
+                _ => height,
+            };
+
+            if new_height != height {
+                changes.push((vertex, Color::from_rgba(new_height, 0.0, 0.0, 1.0)));
+            }
+        }
+        changes
+    }
+
+    // New control pixels keyed by global vertex coordinates
+    fn paint(&self, data: &FastTerrainData, global_position: Vector3) -> Vec<(Vector2i, Color)> {
+        let mut maps = MapCache::new(data, MapType::Control);
+        let mut changes = Vec::new();
+        let texture_id = self.texture_id.clamp(0, MAX_TEXTURES - 1) as u8;
+
+        for (vertex, weight) in self.footprint(data.get_vertex_spacing(), global_position) {
+            let Some(pixel) = maps.get_pixel(vertex).map(ControlPixel::from_color) else {
+                continue;
+            };
+
+            let amount = (self.strength * weight).clamp(0.0, 1.0);
+            let blend = pixel.blend() as f32;
+            let new_pixel = match self.tool {
+                // Replace the base in the brush core and fade out the overlay around it
+                Tool::PaintBase => {
+                    let pixel = if weight >= 0.5 { pixel.with_base(texture_id) } else { pixel };
+                    pixel.with_blend(lerp(blend, 0.0, amount).round() as u8)
+                }
+                Tool::PaintOverlay => {
+                    let pixel = if pixel.overlay() != texture_id {
+                        pixel.with_overlay(texture_id).with_blend(0)
+                    } else {
+                        pixel
+                    };
+                    let blend = pixel.blend() as f32;
+                    pixel.with_blend(lerp(blend, 255.0, amount).round() as u8)
+                }
+                Tool::UvRotation => pixel.with_uv_rotation(self.uv_rotation.clamp(0, 15) as u8),
+                Tool::UvScale => pixel.with_uv_scale(self.uv_scale.clamp(0, 7) as u8),
+                Tool::Holes => pixel.with_hole(self.flag_enabled),
+                Tool::Navigation => pixel.with_nav(self.flag_enabled),
+                Tool::Autoshader => pixel.with_auto(self.flag_enabled),
+                _ => pixel,
+            };
+
+            if new_pixel != pixel {
+                changes.push((vertex, new_pixel.to_color()));
+            }
+        }
+        changes
+    }
+
+    // New color map pixels keyed by global vertex coordinates
+    fn paint_color(&self, data: &FastTerrainData, global_position: Vector3) -> Vec<(Vector2i, Color)> {
+        let mut maps = MapCache::new(data, MapType::Color);
+        let mut changes = Vec::new();
+
+        for (vertex, weight) in self.footprint(data.get_vertex_spacing(), global_position) {
+            let Some(pixel) = maps.get_pixel(vertex) else {
+                continue;
+            };
+
+            let amount = (self.strength * weight).clamp(0.0, 1.0);
+            let mut new_pixel = pixel;
+            match self.tool {
+                Tool::Color => {
+                    new_pixel.r = lerp(pixel.r, self.color.r, amount);
+                    new_pixel.g = lerp(pixel.g, self.color.g, amount);
+                    new_pixel.b = lerp(pixel.b, self.color.b, amount);
+                }
+                Tool::Roughness => new_pixel.a = lerp(pixel.a, self.roughness.clamp(0.0, 1.0), amount),
+                _ => {}
+            }
+
+            if new_pixel != pixel {
+                changes.push((vertex, new_pixel));
+            }
+        }
+        changes
+    }
+
+    // Writes the pixels and returns the changed rect in global vertex coordinates
+    fn apply_changes(
+        &mut self,
+        data: &FastTerrainData,
+        map_type: MapType,
+        changes: Vec<(Vector2i, Color)>,
+    ) -> Option<Rect2i> {
+        if changes.is_empty() {
+            return None;
+        }
+
+        // Save the pixels about to be overwritten, one rect per region
+        let region_size = data.get_region_size();
+        let mut capture_rects: HashMap<Vector2i, Rect2i> = HashMap::new();
+        for (vertex, _) in &changes {
+            let region_loc = Vector2i::new(vertex.x.div_euclid(region_size), vertex.y.div_euclid(region_size));
+            let pixel = Vector2i::new(vertex.x.rem_euclid(region_size), vertex.y.rem_euclid(region_size));
+            let local_rect = Rect2i::new(pixel, Vector2i::ONE);
+            capture_rects
+                .entry(region_loc)
+                .and_modify(|rect| *rect = rect.merge(local_rect))
+                .or_insert(local_rect);
+        }
+        if let Some(edit) = &mut self.edit {
+            let mut edit = edit.bind_mut();
+            for (region_loc, rect) in capture_rects {
+                if data.has_region(region_loc) {
+                    edit.capture(region_loc, map_type, rect);
+                }
+            }
+        }
+
+        let spacing = data.get_vertex_spacing();
+        let mut maps = MapCache::new(data, map_type);
+        let mut changed_rect: Option<Rect2i> = None;
+        let mut stroke_area: Option<Aabb> = None;
+        let mut stroke_ranges: HashMap<Vector2i, Vector2> = HashMap::new();
+
+        for (vertex, color) in changes {
+            let Some(region_loc) = maps.set_pixel(vertex, color) else {
+                continue;
+            };
+
+            let height = if map_type == MapType::Height {
+                let range = stroke_ranges.entry(region_loc).or_insert(Vector2::new(color.r, color.r));
+                range.x = range.x.min(color.r);
+                range.y = range.y.max(color.r);
+                color.r
+            } else {
+                stroke_ranges.entry(region_loc).or_insert(Vector2::ZERO);
+                0.0
+            };
+
+            let pixel_rect = Rect2i::new(vertex, Vector2i::ONE);
+            changed_rect = Some(changed_rect.map_or(pixel_rect, |rect| rect.merge(pixel_rect)));
+            let point = Vector3::new(vertex.x as f32 * spacing, height, vertex.y as f32 * spacing);
+            stroke_area = Some(match stroke_area {
+                Some(area) => area.expand(point),
+                None => Aabb::new(point, Vector3::ZERO),
+            });
+        }
+
+        for (region_loc, range) in stroke_ranges {
+            if let Some((region, _)) = maps.regions.get_mut(&region_loc).and_then(|entry| entry.as_mut()) {
+                let mut region = region.bind_mut();
+                if map_type == MapType::Height {
+                    region.update_heights(range);
+                }
+                region.set_edited(true);
+                region.set_modified(true);
+            }
+            self.edited_regions.insert(region_loc);
+        }
+
+        if let Some(stroke_area) = stroke_area {
+            self.edited_area = Some(match self.edited_area {
+                Some(area) => area.merge(stroke_area),
+                None => stroke_area,
+            });
+        }
+        changed_rect
+    }
+}
+
+type RegionMap = (Gd<FastTerrainRegion>, Gd<Image>);
+
+// Looks up one map type by global vertex coordinates, across region borders
+struct MapCache<'a> {
+    data: &'a FastTerrainData,
+    map_type: MapType,
+    region_size: i32,
+    regions: HashMap<Vector2i, Option<RegionMap>>,
+}
+
+impl<'a> MapCache<'a> {
+    fn new(data: &'a FastTerrainData, map_type: MapType) -> Self {
+        Self {
+            data,
+            map_type,
+            region_size: data.get_region_size(),
+            regions: HashMap::new(),
+        }
+    }
+
+    fn locate(&mut self, vertex: Vector2i) -> Option<(Vector2i, Vector2i, &mut Gd<Image>)> {
+        let region_loc = Vector2i::new(
+            vertex.x.div_euclid(self.region_size),
+            vertex.y.div_euclid(self.region_size),
+        );
+        let pixel = Vector2i::new(
+            vertex.x.rem_euclid(self.region_size),
+            vertex.y.rem_euclid(self.region_size),
+        );
+        let (data, map_type) = (self.data, self.map_type);
+        let entry = self.regions.entry(region_loc).or_insert_with(|| {
+            if !data.has_region(region_loc) {
+                return None;
+            }
+            let region = data.get_region(region_loc)?;
+            let map = region.bind().get_map(map_type)?;
+            Some((region, map))
+        });
+        entry.as_mut().map(|(_, map)| (region_loc, pixel, map))
+    }
+
+    fn get_pixel(&mut self, vertex: Vector2i) -> Option<Color> {
+        let (_, pixel, map) = self.locate(vertex)?;
+        Some(map.get_pixelv(pixel))
+    }
+
+    // Returns the region written to
+    fn set_pixel(&mut self, vertex: Vector2i, color: Color) -> Option<Vector2i> {
+        let (region_loc, pixel, map) = self.locate(vertex)?;
+        map.set_pixelv(pixel, color);
+        Some(region_loc)
+    }
+}
+
+fn lerp(from: f32, to: f32, weight: f32) -> f32 {
+    from + (to - from) * weight
+}
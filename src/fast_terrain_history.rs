@@ -0,0 +1,103 @@
+use godot::prelude::*;
+
+use crate::fast_terrain_edit::FastTerrainEdit;
+
+// Runtime undo history for games and tools running outside the editor
+#[derive(GodotClass)]
+#[class(tool, base=RefCounted)]
+pub struct FastTerrainHistory {
+    #[base]
+    base: Base<RefCounted>,
+
+    #[var(get = get_max_steps, set = set_max_steps)]
+    max_steps: i32,
+    undo_stack: Vec<Gd<FastTerrainEdit>>,
+    redo_stack: Vec<Gd<FastTerrainEdit>>,
+}
+
+#[godot_api]
+impl IRefCounted for FastTerrainHistory {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            base,
+            max_steps: 64,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainHistory {
+    #[func]
+    pub fn set_max_steps(&mut self, steps: i32) {
+        self.max_steps = steps.max(1);
+        self.trim();
+    }
+
+    #[func]
+    pub fn get_max_steps(&self) -> i32 {
+        self.max_steps
+    }
+
+    // Adds an applied edit. Clears anything that could be redone
+    #[func]
+    pub fn push(&mut self, edit: Gd<FastTerrainEdit>) {
+        if edit.bind().is_empty() {
+            return;
+        }
+        self.redo_stack.clear();
+        self.undo_stack.push(edit);
+        self.trim();
+    }
+
+    #[func]
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    #[func]
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    #[func]
+    pub fn undo(&mut self) -> bool {
+        let Some(mut edit) = self.undo_stack.pop() else {
+            return false;
+        };
+        edit.bind_mut().undo();
+        self.redo_stack.push(edit);
+        true
+    }
+
+    #[func]
+    pub fn redo(&mut self) -> bool {
+        let Some(mut edit) = self.redo_stack.pop() else {
+            return false;
+        };
+        edit.bind_mut().redo();
+        self.undo_stack.push(edit);
+        true
+    }
+
+    #[func]
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    #[func]
+    pub fn get_memory_usage(&self) -> i64 {
+        self.undo_stack
+            .iter()
+            .chain(self.redo_stack.iter())
+            .map(|edit| edit.bind().get_memory_usage())
+            .sum()
+    }
+
+    fn trim(&mut self) {
+        let excess = self.undo_stack.len().saturating_sub(self.max_steps as usize);
+        self.undo_stack.drain(..excess);
+    }
+}
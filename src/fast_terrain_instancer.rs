@@ -0,0 +1,791 @@
+use std::collections::{HashMap, HashSet};
+
+use godot::{
+    classes::{
+        rendering_server::{MultimeshTransformFormat, VisibilityRangeFadeMode},
+        Mesh, RandomNumberGenerator, RenderingServer,
+    },
+    prelude::*,
+};
+
+use crate::{
+    fast_terrain_assets::FastTerrainAssets,
+    fast_terrain_data::FastTerrainData,
+    fast_terrain_mesh_asset::FastTerrainMeshAsset,
+    fast_terrain_region::FastTerrainRegion,
+};
+
+// Width of an instance cell in vertices. Each cell of each mesh gets its own multimesh so
+// edits rebuild little and the renderer can cull cells independently
+pub const CELL_SIZE: i32 = 32;
+
+// Multimesh and instance drawing one LOD of a cell
+struct CellMmi {
+    multimesh: Rid,
+    instance: Rid,
+}
+
+impl CellMmi {
+    fn free(&self, rs: &mut Gd<RenderingServer>) {
+        rs.free_rid(self.instance);
+        rs.free_rid(self.multimesh);
+    }
+}
+
+// Brush settings of scatter. Angles are in degrees. Height range is ignored unless x < y
+pub struct ScatterSettings {
+    pub mesh_id: i32,
+    pub radius: f32,
+    // Fraction of the asset density placed per dab
+    pub strength: f32,
+    pub scale_range: Vector2,
+    pub random_spin: f32,
+    pub random_tilt: f32,
+    pub slope_range: Vector2,
+    pub height_range: Vector2,
+    pub seed: i64,
+}
+
+impl ScatterSettings {
+    pub fn new(mesh_id: i32, radius: f32) -> Self {
+        Self {
+            mesh_id,
+            radius,
+            strength: 1.0,
+            scale_range: Vector2::ONE,
+            random_spin: 360.0,
+            random_tilt: 0.0,
+            slope_range: Vector2::new(0.0, 90.0),
+            height_range: Vector2::ZERO,
+            seed: 0,
+        }
+    }
+}
+
+// Renders the instances stored in each region with one MultiMesh per region, mesh id and
+// cell. Region instances are {mesh_id: {cell: [Array[Transform3D], PackedColorArray, modified]}},
+// with transforms in global space
+#[derive(GodotClass)]
+#[class(tool, base=RefCounted)]
+pub struct FastTerrainInstancer {
+    #[base]
+    base: Base<RefCounted>,
+
+    data: Option<Gd<FastTerrainData>>,
+    assets: Option<Gd<FastTerrainAssets>>,
+    scenario: Rid,
+    visible: bool,
+    // One multimesh per LOD of each cell
+    mmis: HashMap<(Vector2i, i32), HashMap<Vector2i, Vec<CellMmi>>>,
+}
+
+#[godot_api]
+impl IRefCounted for FastTerrainInstancer {
+    fn init(base: Base<RefCounted>) -> Self {
+        Self {
+            base,
+            data: None,
+            assets: None,
+            scenario: Rid::Invalid,
+            visible: true,
+            mmis: HashMap::new(),
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainInstancer {
+    #[func]
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+        let mut rs = RenderingServer::singleton();
+        for cell in self.mmis.values().flat_map(|cells| cells.values()).flatten() {
+            rs.instance_set_visible(cell.instance, visible);
+        }
+    }
+
+    // Frees every multimesh. Instance data in the regions is untouched
+    #[func]
+    pub fn destroy(&mut self) {
+        if self.mmis.is_empty() {
+            return;
+        }
+        godot_print!("Destroying instancer multimeshes");
+        let mut rs = RenderingServer::singleton();
+        for (_, cells) in self.mmis.drain() {
+            for cell in cells.values().flatten() {
+                cell.free(&mut rs);
+            }
+        }
+    }
+
+    // Rebuilds the multimeshes of every active region
+    #[func]
+    pub fn update_mmis(&mut self) {
+        self.destroy();
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+        if !self.scenario.is_valid() {
+            return;
+        }
+
+        let regions = data.bind().get_regions_active();
+        godot_print!("Updating instancer for {} regions", regions.len());
+        for region in regions.iter_shared() {
+            let region_loc = region.bind().get_location();
+            self.update_region(region_loc);
+        }
+    }
+
+    // Rebuilds the multimeshes of one region, freeing those of mesh ids it no longer has
+    #[func]
+    pub fn update_region(&mut self, region_loc: Vector2i) {
+        let stale: Vec<(Vector2i, i32)> = self.mmis.keys().filter(|key| key.0 == region_loc).copied().collect();
+        for key in stale {
+            self.free_cells(key);
+        }
+
+        let Some(region) = self.get_active_region(region_loc) else {
+            return;
+        };
+        let instances = region.bind().get_instances();
+        for (mesh_id, cells) in instances.iter_shared() {
+            let (Ok(mesh_id), Ok(cells)) = (mesh_id.try_to::<i32>(), cells.try_to::<Dictionary>()) else {
+                continue;
+            };
+            for cell in cells.keys_array().iter_shared() {
+                if let Ok(cell) = cell.try_to::<Vector2i>() {
+                    self.update_cell(region_loc, mesh_id, cell);
+                }
+            }
+        }
+    }
+
+    // Appends global transforms to the regions and cells they fall in. Colors are optional
+    // and default to white
+    #[func]
+    pub fn add_transforms(&mut self, mesh_id: i32, transforms: Array<Transform3D>, colors: PackedColorArray) {
+        if !self.is_valid_mesh_id(mesh_id) {
+            godot_error!("Mesh id {} has no mesh asset. Cannot add instances", mesh_id);
+            return;
+        }
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+
+        // Group by region and cell first so each cell is written and rebuilt once
+        let mut grouped: HashMap<(Vector2i, Vector2i), (Vec<Transform3D>, Vec<Color>)> = HashMap::new();
+        let mut locked = HashSet::new();
+        for (i, transform) in transforms.iter_shared().enumerate() {
+            let region_loc = data.bind().get_region_location(transform.origin);
+            let Some(region) = self.get_active_region(region_loc) else {
+                continue;
+            };
+            // Adding would replace the undecoded data when the region is saved
+            if region.bind().has_undecoded_instances() {
+                if locked.insert(region_loc) {
+                    godot_error!("Region {} holds instance data that can't be read. Not adding instances to it", region_loc);
+                }
+                continue;
+            }
+            let cell = self.get_cell(&data.bind(), region_loc, transform.origin);
+            let color = colors.get(i).unwrap_or(Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+            let entry = grouped.entry((region_loc, cell)).or_default();
+            entry.0.push(transform);
+            entry.1.push(color);
+        }
+
+        let mut added = 0;
+        for ((region_loc, cell), (new_transforms, new_colors)) in grouped {
+            let Some(mut region) = self.get_active_region(region_loc) else {
+                continue;
+            };
+            let (mut cell_transforms, mut cell_colors) = Self::get_cell_data(&region.bind(), mesh_id, cell);
+            added += new_transforms.len();
+            cell_transforms.extend(new_transforms);
+            cell_colors.extend(new_colors);
+            Self::set_cell_data(&mut region, mesh_id, cell, &cell_transforms, &cell_colors);
+            self.update_cell(region_loc, mesh_id, cell);
+        }
+        godot_print!("Added {} instances of mesh id {}", added, mesh_id);
+    }
+
+    // Scatters the mesh over a circle at its asset density, with the default scatter
+    // settings. Returns the number of instances added
+    #[func]
+    pub fn add_instances(&mut self, global_position: Vector3, radius: f32, mesh_id: i32) -> i32 {
+        self.scatter(global_position, &ScatterSettings::new(mesh_id, radius), |_| 1.0)
+    }
+
+    // Removes strength, 0 to 1, of the instances within radius. Mesh id -1 thins every
+    // mesh. Returns the number of instances removed
+    #[func]
+    pub fn remove_instances(&mut self, global_position: Vector3, radius: f32, mesh_id: i32, strength: f32) -> i32 {
+        self.thin(global_position, radius, mesh_id, strength, 0, |_| 1.0)
+    }
+
+    #[func]
+    pub fn clear_by_mesh(&mut self, mesh_id: i32) {
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+        godot_print!("Clearing instances of mesh id {}", mesh_id);
+        let regions = data.bind().get_regions_active();
+        for region in regions.iter_shared() {
+            let region_loc = region.bind().get_location();
+            self.clear_by_location(region_loc, mesh_id);
+        }
+    }
+
+    #[func]
+    pub fn clear_by_location(&mut self, region_loc: Vector2i, mesh_id: i32) {
+        let Some(mut region) = self.get_active_region(region_loc) else {
+            return;
+        };
+        let mut instances = region.bind().get_instances();
+        if instances.remove(mesh_id).is_some() {
+            region.bind_mut().set_modified(true);
+        }
+        self.free_cells((region_loc, mesh_id));
+    }
+
+    // Exchanges two mesh ids in every region, following a swap in the asset list
+    #[func]
+    pub fn swap_ids(&mut self, src_id: i32, dst_id: i32) {
+        godot_print!("Swapping instance mesh ids {} and {}", src_id, dst_id);
+        self.remap_ids(|id| {
+            if id == src_id {
+                Some(dst_id)
+            } else if id == dst_id {
+                Some(src_id)
+            } else {
+                Some(id)
+            }
+        });
+    }
+
+    // Deletes the instances of a removed mesh asset and shifts higher ids down to match the
+    // compacted asset list
+    #[func]
+    pub fn remove_mesh_id(&mut self, mesh_id: i32) {
+        godot_print!("Removing instances of mesh id {}", mesh_id);
+        self.remap_ids(|id| match id.cmp(&mesh_id) {
+            std::cmp::Ordering::Less => Some(id),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some(id - 1),
+        });
+    }
+
+    // Instances of a mesh id across active regions, or of every mesh id if -1
+    #[func]
+    pub fn get_instance_count(&self, mesh_id: i32) -> i32 {
+        let Some(data) = &self.data else {
+            return 0;
+        };
+        let mut count = 0;
+        for region in data.bind().get_regions_active().iter_shared() {
+            for (id, cells) in region.bind().get_instances().iter_shared() {
+                if mesh_id >= 0 && id.try_to::<i32>().ok() != Some(mesh_id) {
+                    continue;
+                }
+                let Ok(cells) = cells.try_to::<Dictionary>() else {
+                    continue;
+                };
+                for (_, cell) in cells.iter_shared() {
+                    if let Ok(cell) = cell.try_to::<VariantArray>() {
+                        count += Self::cell_transforms(&cell).len() as i32;
+                    }
+                }
+            }
+        }
+        count
+    }
+}
+
+impl FastTerrainInstancer {
+    pub fn initialize(&mut self, data: Gd<FastTerrainData>, scenario: Rid) {
+        self.data = Some(data);
+        self.scenario = scenario;
+    }
+
+    pub fn set_assets(&mut self, assets: Option<Gd<FastTerrainAssets>>) {
+        self.assets = assets;
+    }
+
+    // Places instances over a circle at the mesh asset density times strength, never past
+    // the asset density and never closer than half the average spacing to another instance.
+    // Candidates are kept by weight, given the offset from the center with both axes in -1
+    // to 1, and by the slope and height limits. The same seed, center and existing instances
+    // always give the same new instances. Returns the number added
+    pub fn scatter(&mut self, global_position: Vector3, settings: &ScatterSettings, weight: impl Fn(Vector2) -> f32) -> i32 {
+        let Some(mesh_asset) = self.get_mesh_asset(settings.mesh_id) else {
+            godot_error!("Mesh id {} has no mesh asset. Cannot add instances", settings.mesh_id);
+            return 0;
+        };
+        let Some(data) = self.data.clone() else {
+            return 0;
+        };
+
+        let radius = settings.radius.max(0.0);
+        let density = mesh_asset.bind().get_density();
+        let area = std::f32::consts::PI * radius * radius;
+        let min_distance = 0.5 / density.sqrt();
+        let center = Vector2::new(global_position.x, global_position.z);
+
+        // Top up to the target density rather than stacking every dab on the last
+        let mut nearby = self.positions_in_radius(&data.bind(), center, radius + min_distance, settings.mesh_id);
+        let existing = nearby.iter().filter(|position| position.distance_to(center) <= radius).count() as i32;
+        let target = (density * area).round() as i32;
+        let count = ((density * settings.strength.clamp(0.0, 1.0) * area).round() as i32).min(target - existing);
+        if count <= 0 {
+            return 0;
+        }
+
+        let seed = settings.seed.wrapping_add(existing as i64);
+        let mut rng = Self::seeded_rng(seed, global_position, data.bind().get_vertex_spacing());
+        let check_height = settings.height_range.x < settings.height_range.y;
+
+        let mut transforms = Array::new();
+        for _ in 0..count {
+            // Draw every random value up front so rejected candidates don't shift the sequence
+            let distance = radius * rng.randf().sqrt();
+            let angle = rng.randf() * std::f32::consts::TAU;
+            let keep = rng.randf();
+            let spin = rng.randf() * settings.random_spin.to_radians();
+            let tilt_axis = rng.randf() * std::f32::consts::TAU;
+            let tilt = rng.randf() * settings.random_tilt.to_radians();
+            let scale = rng.randf_range(settings.scale_range.x, settings.scale_range.y);
+
+            let offset = Vector2::new(angle.cos(), angle.sin()) * distance;
+            if radius > 0.0 && keep >= weight(offset / radius) {
+                continue;
+            }
+            let candidate = center + offset;
+            if nearby.iter().any(|position| position.distance_to(candidate) < min_distance) {
+                continue;
+            }
+            let mut position = global_position + Vector3::new(offset.x, 0.0, offset.y);
+            let (height, normal) = {
+                let data = data.bind();
+                (data.get_height(position), data.get_normal(position))
+            };
+            if height.is_nan() || (check_height && !(settings.height_range.x..=settings.height_range.y).contains(&height)) {
+                continue;
+            }
+            let slope = normal.y.clamp(-1.0, 1.0).acos().to_degrees();
+            if !(settings.slope_range.x..=settings.slope_range.y).contains(&slope) {
+                continue;
+            }
+            position.y = height;
+
+            let axis = Vector3::new(tilt_axis.cos(), 0.0, tilt_axis.sin());
+            let basis = Basis::from_axis_angle(axis, tilt) * Basis::from_axis_angle(Vector3::UP, spin);
+            transforms.push(Transform3D::new(basis.scaled(Vector3::splat(scale.max(0.01))), position));
+            nearby.push(candidate);
+        }
+
+        let added = transforms.len() as i32;
+        if added > 0 {
+            self.add_transforms(settings.mesh_id, transforms, PackedColorArray::new());
+        }
+        added
+    }
+
+    // Removes instances within radius, each with a chance of strength times its weight.
+    // Strength 1 with a flat weight erases them all. Returns the number removed
+    pub fn thin(
+        &mut self,
+        global_position: Vector3,
+        radius: f32,
+        mesh_id: i32,
+        strength: f32,
+        seed: i64,
+        weight: impl Fn(Vector2) -> f32,
+    ) -> i32 {
+        let Some(data) = self.data.clone() else {
+            return 0;
+        };
+        let (spacing, region_size) = {
+            let data = data.bind();
+            (data.get_vertex_spacing(), data.get_region_size())
+        };
+        let mut rng = Self::seeded_rng(seed, global_position, spacing);
+        let center = Vector2::new(global_position.x, global_position.z);
+        let radius = radius.max(0.0);
+
+        let mut removed = 0;
+        for region_loc in Self::regions_in_radius(&data.bind(), global_position, radius) {
+            let Some(mut region) = self.get_active_region(region_loc) else {
+                continue;
+            };
+            let instances = region.bind().get_instances();
+            // Keys up front, as emptied mesh ids are removed from the dictionary below
+            for id in instances.keys_array().iter_shared() {
+                let Ok(id) = id.try_to::<i32>() else {
+                    continue;
+                };
+                if mesh_id >= 0 && id != mesh_id {
+                    continue;
+                }
+                let Some(cells) = instances.get(id).and_then(|cells| cells.try_to::<Dictionary>().ok()) else {
+                    continue;
+                };
+                for cell in cells.keys_array().iter_shared() {
+                    let Ok(cell) = cell.try_to::<Vector2i>() else {
+                        continue;
+                    };
+                    if !Self::cell_in_radius(spacing, region_size, region_loc, cell, center, radius) {
+                        continue;
+                    }
+
+                    let (transforms, colors) = Self::get_cell_data(&region.bind(), id, cell);
+                    let mut kept_transforms = Vec::with_capacity(transforms.len());
+                    let mut kept_colors = Vec::with_capacity(colors.len());
+                    for (transform, color) in transforms.iter().zip(colors.iter()) {
+                        let offset = Vector2::new(transform.origin.x, transform.origin.z) - center;
+                        let inside = offset.length() <= radius;
+                        let chance = if radius > 0.0 { strength * weight(offset / radius) } else { strength };
+                        if inside && rng.randf() < chance {
+                            continue;
+                        }
+                        kept_transforms.push(*transform);
+                        kept_colors.push(*color);
+                    }
+
+                    if kept_transforms.len() < transforms.len() {
+                        removed += (transforms.len() - kept_transforms.len()) as i32;
+                        Self::set_cell_data(&mut region, id, cell, &kept_transforms, &kept_colors);
+                        self.update_cell(region_loc, id, cell);
+                    }
+                }
+            }
+        }
+        if removed > 0 {
+            godot_print!("Removed {} instances", removed);
+        }
+        removed
+    }
+
+    // Active regions a circle overlaps, so callers can capture them before editing
+    pub fn regions_in_radius(data: &FastTerrainData, global_position: Vector3, radius: f32) -> Vec<Vector2i> {
+        let min = data.get_region_location(global_position - Vector3::new(radius, 0.0, radius));
+        let max = data.get_region_location(global_position + Vector3::new(radius, 0.0, radius));
+        let mut regions = Vec::new();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let region_loc = Vector2i::new(x, y);
+                if data.has_region(region_loc) {
+                    regions.push(region_loc);
+                }
+            }
+        }
+        regions
+    }
+
+    // Cells of a region a circle reaches into, so callers can capture them before editing
+    pub fn cells_in_radius(data: &FastTerrainData, region_loc: Vector2i, global_position: Vector3, radius: f32) -> Vec<Vector2i> {
+        let (spacing, region_size) = (data.get_vertex_spacing(), data.get_region_size());
+        let center = Vector2::new(global_position.x, global_position.z);
+        let mut cells = Vec::new();
+        for y in 0..region_size / CELL_SIZE {
+            for x in 0..region_size / CELL_SIZE {
+                let cell = Vector2i::new(x, y);
+                if Self::cell_in_radius(spacing, region_size, region_loc, cell, center, radius) {
+                    cells.push(cell);
+                }
+            }
+        }
+        cells
+    }
+
+    // XZ positions of the instances of a mesh id within radius of a global XZ center
+    fn positions_in_radius(&self, data: &FastTerrainData, center: Vector2, radius: f32, mesh_id: i32) -> Vec<Vector2> {
+        let (spacing, region_size) = (data.get_vertex_spacing(), data.get_region_size());
+        let mut positions = Vec::new();
+        for region_loc in Self::regions_in_radius(data, Vector3::new(center.x, 0.0, center.y), radius) {
+            let Some(region) = self.get_active_region(region_loc) else {
+                continue;
+            };
+            let cells = region
+                .bind()
+                .get_instances()
+                .get(mesh_id)
+                .and_then(|cells| cells.try_to::<Dictionary>().ok())
+                .unwrap_or_default();
+            for (cell, cell_data) in cells.iter_shared() {
+                let (Ok(cell), Ok(cell_data)) = (cell.try_to::<Vector2i>(), cell_data.try_to::<VariantArray>()) else {
+                    continue;
+                };
+                if !Self::cell_in_radius(spacing, region_size, region_loc, cell, center, radius) {
+                    continue;
+                }
+                positions.extend(
+                    Self::cell_transforms(&cell_data)
+                        .iter()
+                        .map(|transform| Vector2::new(transform.origin.x, transform.origin.z))
+                        .filter(|position| position.distance_to(center) <= radius),
+                );
+            }
+        }
+        positions
+    }
+
+    // Whether a circle around a global XZ center reaches into a cell
+    fn cell_in_radius(spacing: f32, region_size: i32, region_loc: Vector2i, cell: Vector2i, center: Vector2, radius: f32) -> bool {
+        let cell_min = Vector2::new(
+            (region_loc.x * region_size + cell.x * CELL_SIZE) as f32,
+            (region_loc.y * region_size + cell.y * CELL_SIZE) as f32,
+        ) * spacing;
+        let nearest = center.clamp(cell_min, cell_min + Vector2::splat(CELL_SIZE as f32 * spacing));
+        nearest.distance_to(center) <= radius
+    }
+
+    // Seeds from the seed and the vertex under the brush, so a stroke repeated over the same
+    // path and starting instances gives the same result
+    fn seeded_rng(seed: i64, global_position: Vector3, spacing: f32) -> Gd<RandomNumberGenerator> {
+        let x = (global_position.x / spacing).round() as i64 as u64;
+        let z = (global_position.z / spacing).round() as i64 as u64;
+        let mut hash = (seed as u64) ^ x.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ z.wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        hash ^= hash >> 30;
+        hash = hash.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        hash ^= hash >> 27;
+        hash = hash.wrapping_mul(0x94D0_49BB_1331_11EB);
+        hash ^= hash >> 31;
+
+        let mut rng = RandomNumberGenerator::new_gd();
+        rng.set_seed(hash);
+        rng
+    }
+
+    fn get_active_region(&self, region_loc: Vector2i) -> Option<Gd<FastTerrainRegion>> {
+        let data = self.data.as_ref()?.bind();
+        if data.has_region(region_loc) {
+            data.get_region(region_loc)
+        } else {
+            None
+        }
+    }
+
+    fn get_mesh_asset(&self, mesh_id: i32) -> Option<Gd<FastTerrainMeshAsset>> {
+        self.assets.as_ref()?.bind().get_mesh_asset(mesh_id)
+    }
+
+    fn is_valid_mesh_id(&self, mesh_id: i32) -> bool {
+        self.get_mesh_asset(mesh_id).is_some()
+    }
+
+    // Cell of a global position, relative to its region
+    fn get_cell(&self, data: &FastTerrainData, region_loc: Vector2i, global_position: Vector3) -> Vector2i {
+        let spacing = data.get_vertex_spacing();
+        let region_size = data.get_region_size();
+        let local = Vector2::new(global_position.x, global_position.z) / spacing
+            - Vector2::new(region_loc.x as f32, region_loc.y as f32) * region_size as f32;
+        Vector2i::new(
+            (local.x as i32 / CELL_SIZE).clamp(0, region_size / CELL_SIZE - 1),
+            (local.y as i32 / CELL_SIZE).clamp(0, region_size / CELL_SIZE - 1),
+        )
+    }
+
+    fn cell_transforms(cell: &VariantArray) -> Vec<Transform3D> {
+        cell.get(0)
+            .and_then(|transforms| transforms.try_to::<VariantArray>().ok())
+            .map(|transforms| {
+                transforms
+                    .iter_shared()
+                    .filter_map(|transform| transform.try_to::<Transform3D>().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn get_cell_data(region: &FastTerrainRegion, mesh_id: i32, cell: Vector2i) -> (Vec<Transform3D>, Vec<Color>) {
+        let cell = region
+            .get_instances()
+            .get(mesh_id)
+            .and_then(|cells| cells.try_to::<Dictionary>().ok())
+            .and_then(|cells| cells.get(cell))
+            .and_then(|cell| cell.try_to::<VariantArray>().ok());
+        let Some(cell) = cell else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let transforms = Self::cell_transforms(&cell);
+        let mut colors: Vec<Color> = cell
+            .get(1)
+            .and_then(|colors| colors.try_to::<PackedColorArray>().ok())
+            .map(|colors| colors.as_slice().to_vec())
+            .unwrap_or_default();
+        colors.resize(transforms.len(), Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+        (transforms, colors)
+    }
+
+    // Writes a cell, or removes it when empty, and marks the region modified
+    fn set_cell_data(
+        region: &mut Gd<FastTerrainRegion>,
+        mesh_id: i32,
+        cell: Vector2i,
+        transforms: &[Transform3D],
+        colors: &[Color],
+    ) {
+        let mut instances = region.bind().get_instances();
+        let mut cells = instances
+            .get(mesh_id)
+            .and_then(|cells| cells.try_to::<Dictionary>().ok())
+            .unwrap_or_default();
+
+        if transforms.is_empty() {
+            cells.remove(cell);
+        } else {
+            let transforms: Array<Transform3D> = transforms.iter().copied().collect();
+            let colors: PackedColorArray = colors.iter().copied().collect();
+            let cell_data: VariantArray = [transforms.to_variant(), colors.to_variant(), true.to_variant()]
+                .into_iter()
+                .collect();
+            cells.set(cell, cell_data);
+        }
+
+        if cells.is_empty() {
+            instances.remove(mesh_id);
+        } else {
+            instances.set(mesh_id, cells);
+        }
+        region.bind_mut().set_modified(true);
+    }
+
+    fn free_cells(&mut self, key: (Vector2i, i32)) {
+        let Some(cells) = self.mmis.remove(&key) else {
+            return;
+        };
+        let mut rs = RenderingServer::singleton();
+        for cell in cells.values().flatten() {
+            cell.free(&mut rs);
+        }
+    }
+
+    // Uploads one cell to a multimesh per LOD, creating or freeing them as needed
+    fn update_cell(&mut self, region_loc: Vector2i, mesh_id: i32, cell: Vector2i) {
+        if !self.scenario.is_valid() {
+            return;
+        }
+        let mut rs = RenderingServer::singleton();
+        let key = (region_loc, mesh_id);
+
+        let mesh_asset = self.get_mesh_asset(mesh_id);
+        let (transforms, colors) = match self.get_active_region(region_loc) {
+            Some(region) => Self::get_cell_data(&region.bind(), mesh_id, cell),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        // Mesh and draw range of each LOD
+        let lods: Vec<(Gd<Mesh>, Vector2)> = mesh_asset
+            .as_ref()
+            .map(|asset| {
+                let asset = asset.bind();
+                (0..asset.get_lod_count())
+                    .filter_map(|lod| Some((asset.get_mesh(lod)?, asset.get_lod_range(lod))))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let existing = self.mmis.get_mut(&key).and_then(|cells| cells.remove(&cell)).unwrap_or_default();
+        let (Some(mesh_asset), false, false) = (mesh_asset, lods.is_empty(), transforms.is_empty()) else {
+            for lod in &existing {
+                lod.free(&mut rs);
+            }
+            return;
+        };
+
+        let (height_offset, visibility_margin, cast_shadows) = {
+            let asset = mesh_asset.bind();
+            (asset.get_height_offset(), asset.get_visibility_margin(), asset.get_cast_shadows())
+        };
+
+        // 12 floats of transform rows and origin, then 4 of color, per instance
+        let mut buffer = PackedFloat32Array::new();
+        buffer.resize(transforms.len() * 16);
+        let values = buffer.as_mut_slice();
+        for (i, (transform, color)) in transforms.iter().zip(colors.iter()).enumerate() {
+            let rows = transform.basis.rows;
+            let origin = transform.origin + Vector3::UP * height_offset;
+            values[i * 16..(i + 1) * 16].copy_from_slice(&[
+                rows[0].x, rows[0].y, rows[0].z, origin.x,
+                rows[1].x, rows[1].y, rows[1].z, origin.y,
+                rows[2].x, rows[2].y, rows[2].z, origin.z,
+                color.r, color.g, color.b, color.a,
+            ]);
+        }
+
+        // Reuse the multimeshes when the LOD count is unchanged
+        let mut cell_mmis = existing;
+        if cell_mmis.len() != lods.len() {
+            for lod in cell_mmis.drain(..) {
+                lod.free(&mut rs);
+            }
+            for _ in 0..lods.len() {
+                let multimesh = rs.multimesh_create();
+                let instance = rs.instance_create2(multimesh, self.scenario);
+                cell_mmis.push(CellMmi { multimesh, instance });
+            }
+        }
+
+        for (lod, (cell_mmi, (mesh, range))) in cell_mmis.iter().zip(lods.iter()).enumerate() {
+            rs.multimesh_set_mesh(cell_mmi.multimesh, mesh.get_rid());
+            rs.multimesh_allocate_data_ex(cell_mmi.multimesh, transforms.len() as i32, MultimeshTransformFormat::TRANSFORM_3D)
+                .color_format(true)
+                .done();
+            rs.multimesh_set_buffer(cell_mmi.multimesh, &buffer);
+
+            rs.instance_geometry_set_cast_shadows_setting(cell_mmi.instance, cast_shadows);
+            // Margins overlap neighbouring LODs, except before the first
+            rs.instance_geometry_set_visibility_range(
+                cell_mmi.instance,
+                range.x,
+                range.y,
+                if lod == 0 { 0.0 } else { visibility_margin },
+                visibility_margin,
+                VisibilityRangeFadeMode::DISABLED,
+            );
+            rs.instance_set_visible(cell_mmi.instance, self.visible);
+        }
+        self.mmis.entry(key).or_default().insert(cell, cell_mmis);
+    }
+
+    // Rewrites the mesh id keys of every region. None drops that id's instances
+    fn remap_ids(&mut self, remap: impl Fn(i32) -> Option<i32>) {
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+        let regions = data.bind().get_regions_active();
+        for mut region in regions.iter_shared() {
+            let instances = region.bind().get_instances();
+            let mut remapped = Dictionary::new();
+            let mut changed = false;
+            for (id, cells) in instances.iter_shared() {
+                let Ok(id) = id.try_to::<i32>() else {
+                    continue;
+                };
+                match remap(id) {
+                    Some(new_id) => {
+                        changed |= new_id != id;
+                        remapped.set(new_id, cells);
+                    }
+                    None => changed = true,
+                }
+            }
+            if changed {
+                region.bind_mut().set_instances(remapped);
+                region.bind_mut().set_modified(true);
+            }
+        }
+        // Deferred, as the asset list calling this is still bound and mesh lookups need it
+        self.destroy();
+        self.base_mut().call_deferred("update_mmis", &[]);
+    }
+}
+
+impl Drop for FastTerrainInstancer {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
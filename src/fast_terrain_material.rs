@@ -0,0 +1,214 @@
+use godot::{classes::RenderingServer, prelude::*};
+
+use crate::{
+    fast_terrain_assets::{FastTerrainAssets, MAX_TEXTURES},
+    fast_terrain_data::FastTerrainData,
+};
+
+const TERRAIN_SHADER: &str = include_str!("shaders/terrain.gdshader");
+
+// Owns the terrain shader and the material applied to every clipmap instance
+#[derive(GodotClass)]
+#[class(tool, base=Resource)]
+pub struct FastTerrainMaterial {
+    #[base]
+    base: Base<Resource>,
+
+    // Blend per pixel between the auto base and overlay textures by slope, where the
+    // control map auto flag is set
+    #[export]
+    #[var(get = get_auto_shader, set = set_auto_shader)]
+    auto_shader: bool,
+    #[export(range = (0.0, 10.0))]
+    #[var(get = get_auto_slope, set = set_auto_slope)]
+    auto_slope: f32,
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_auto_height_reduction, set = set_auto_height_reduction)]
+    auto_height_reduction: f32,
+    #[export(range = (0.0, 31.0))]
+    #[var(get = get_auto_base_texture, set = set_auto_base_texture)]
+    auto_base_texture: i32,
+    #[export(range = (0.0, 31.0))]
+    #[var(get = get_auto_overlay_texture, set = set_auto_overlay_texture)]
+    auto_overlay_texture: i32,
+    // How much texture heights sharpen the transition between base and overlay
+    #[export(range = (0.0, 1.0))]
+    #[var(get = get_blend_sharpness, set = set_blend_sharpness)]
+    blend_sharpness: f32,
+
+    shader: Rid,
+    material: Rid,
+}
+
+#[godot_api]
+impl IResource for FastTerrainMaterial {
+    fn init(base: Base<Resource>) -> Self {
+        Self {
+            base,
+            auto_shader: false,
+            auto_slope: 1.0,
+            auto_height_reduction: 0.1,
+            auto_base_texture: 0,
+            auto_overlay_texture: 1,
+            blend_sharpness: 0.87,
+            shader: Rid::Invalid,
+            material: Rid::Invalid,
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrainMaterial {
+    #[func]
+    pub fn get_material_rid(&mut self) -> Rid {
+        self.initialize();
+        self.material
+    }
+
+    #[func]
+    pub fn get_shader_rid(&mut self) -> Rid {
+        self.initialize();
+        self.shader
+    }
+
+    #[func]
+    pub fn get_shader_code(&self) -> GString {
+        TERRAIN_SHADER.into()
+    }
+
+    #[func]
+    pub fn set_auto_shader(&mut self, enabled: bool) {
+        self.auto_shader = enabled;
+        self.set_param("auto_shader", enabled.to_variant());
+    }
+
+    #[func]
+    pub fn get_auto_shader(&self) -> bool {
+        self.auto_shader
+    }
+
+    #[func]
+    pub fn set_auto_slope(&mut self, slope: f32) {
+        self.auto_slope = slope.max(0.0);
+        self.set_param("auto_slope", self.auto_slope.to_variant());
+    }
+
+    #[func]
+    pub fn get_auto_slope(&self) -> f32 {
+        self.auto_slope
+    }
+
+    #[func]
+    pub fn set_auto_height_reduction(&mut self, reduction: f32) {
+        self.auto_height_reduction = reduction.clamp(0.0, 1.0);
+        self.set_param("auto_height_reduction", self.auto_height_reduction.to_variant());
+    }
+
+    #[func]
+    pub fn get_auto_height_reduction(&self) -> f32 {
+        self.auto_height_reduction
+    }
+
+    #[func]
+    pub fn set_auto_base_texture(&mut self, id: i32) {
+        self.auto_base_texture = id.clamp(0, MAX_TEXTURES - 1);
+        self.set_param("auto_base_texture", self.auto_base_texture.to_variant());
+    }
+
+    #[func]
+    pub fn get_auto_base_texture(&self) -> i32 {
+        self.auto_base_texture
+    }
+
+    #[func]
+    pub fn set_auto_overlay_texture(&mut self, id: i32) {
+        self.auto_overlay_texture = id.clamp(0, MAX_TEXTURES - 1);
+        self.set_param("auto_overlay_texture", self.auto_overlay_texture.to_variant());
+    }
+
+    #[func]
+    pub fn get_auto_overlay_texture(&self) -> i32 {
+        self.auto_overlay_texture
+    }
+
+    #[func]
+    pub fn set_blend_sharpness(&mut self, sharpness: f32) {
+        self.blend_sharpness = sharpness.clamp(0.0, 1.0);
+        self.set_param("blend_sharpness", self.blend_sharpness.to_variant());
+    }
+
+    #[func]
+    pub fn get_blend_sharpness(&self) -> f32 {
+        self.blend_sharpness
+    }
+}
+
+impl FastTerrainMaterial {
+    fn initialize(&mut self) {
+        if self.material.is_valid() {
+            return;
+        }
+
+        godot_print!("Creating terrain shader and material");
+        let mut rs = RenderingServer::singleton();
+        self.shader = rs.shader_create();
+        rs.shader_set_code(self.shader, TERRAIN_SHADER);
+        self.material = rs.material_create();
+        rs.material_set_shader(self.material, self.shader);
+        self.update_settings();
+    }
+
+    fn set_param(&mut self, name: &str, value: Variant) {
+        if self.material.is_valid() {
+            RenderingServer::singleton().material_set_param(self.material, name, &value);
+        }
+    }
+
+    fn update_settings(&mut self) {
+        self.set_param("auto_shader", self.auto_shader.to_variant());
+        self.set_param("auto_slope", self.auto_slope.to_variant());
+        self.set_param("auto_height_reduction", self.auto_height_reduction.to_variant());
+        self.set_param("auto_base_texture", self.auto_base_texture.to_variant());
+        self.set_param("auto_overlay_texture", self.auto_overlay_texture.to_variant());
+        self.set_param("blend_sharpness", self.blend_sharpness.to_variant());
+    }
+
+    // Points the shader at the generated region maps and the texture asset arrays
+    pub fn update(&mut self, data: &FastTerrainData, assets: Option<&FastTerrainAssets>) {
+        self.initialize();
+        godot_print!("Updating terrain material for {} regions", data.get_region_count());
+
+        let mut region_map = data.get_region_map();
+        region_map.resize((FastTerrainData::REGION_MAP_SIZE * FastTerrainData::REGION_MAP_SIZE) as usize);
+        self.set_param("_region_size", (data.get_region_size() as f32).to_variant());
+        self.set_param("_vertex_spacing", data.get_vertex_spacing().to_variant());
+        self.set_param("_region_map_size", FastTerrainData::REGION_MAP_SIZE.to_variant());
+        self.set_param("_region_map", region_map.to_variant());
+        self.set_param("_height_maps", data.get_height_maps_rid().to_variant());
+        self.set_param("_control_maps", data.get_control_maps_rid().to_variant());
+        self.set_param("_color_maps", data.get_color_maps_rid().to_variant());
+
+        let Some(assets) = assets else {
+            self.set_param("_texture_count", 0.to_variant());
+            return;
+        };
+        self.set_param("_texture_count", assets.get_texture_count().to_variant());
+        self.set_param("_texture_array_albedo", assets.get_albedo_array_rid().to_variant());
+        self.set_param("_texture_array_normal", assets.get_normal_array_rid().to_variant());
+        self.set_param("_texture_uv_scale_array", assets.get_texture_uv_scales().to_variant());
+        self.set_param("_texture_detile_array", assets.get_texture_detiles().to_variant());
+        self.set_param("_texture_color_array", assets.get_texture_colors().to_variant());
+    }
+}
+
+impl Drop for FastTerrainMaterial {
+    fn drop(&mut self) {
+        let mut rs = RenderingServer::singleton();
+        if self.material.is_valid() {
+            rs.free_rid(self.material);
+        }
+        if self.shader.is_valid() {
+            rs.free_rid(self.shader);
+        }
+    }
+}
@@ -1,20 +1,40 @@
-use godot::classes::base_material_3d::{CullMode, DistanceFadeMode, Feature, Flags, Transparency};
+use godot::classes::base_material_3d::{
+    BillboardMode, CullMode, DistanceFadeMode, Feature, Flags, TextureParam, Transparency,
+};
 use godot::classes::mesh::{ArrayType, PrimitiveType};
 use godot::classes::rendering_server::ShadowCastingSetting;
-use godot::classes::{ArrayMesh, ImageTexture, Material, Mesh, MeshInstance3D, StandardMaterial3D};
+use godot::classes::{ArrayMesh, ImageTexture, Material, Mesh, MeshInstance3D, StandardMaterial3D, Texture2D};
 use godot::meta::ParamType;
 use godot::prelude::*;
 use crate::fast_terrain_assets::{AssetType, MAX_MESHES};
 use crate::fast_terrain_assets_resource::{FastTerrainAssetResource, FastTerrainAssetResourceImpl};
 
-#[derive(GodotConvert, Var, Export, PartialEq, Debug)]
+// Scene meshes beyond this many LODs are ignored
+pub const MAX_LODS: i32 = 10;
+
+#[derive(GodotConvert, Var, Export, Clone, Copy, PartialEq, Debug)]
 #[godot(via = GString)]
-enum GenType {
+pub enum GenType {
     None,
+    // Vertical quads crossing at the center, rotated evenly over half a turn
     TextureCard,
+    // One quad the default material turns to face the camera
+    Billboard,
+    // Quads fanning out from the center, rotated evenly over a full turn
+    Star,
+    // Strips tapering to a tip, rotated like texture cards
+    GrassBlade,
     Max,
 }
 
+// One vertical strip of a generated mesh, from left to right along its local X axis
+struct Strip {
+    angle: f32,
+    left: f32,
+    right: f32,
+    taper: bool,
+}
+
 #[derive(GodotClass)]
 #[class(tool, base=Resource)]
 pub struct FastTerrainMeshAsset {
@@ -29,12 +49,23 @@ pub struct FastTerrainMeshAsset {
     cast_shadows: ShadowCastingSetting,
     generated_faces: i32,
     generated_size: Vector2,
+    // Rows of quads up each strip, so wind shaders have vertices to bend
+    generated_subdivisions: i32,
+    // Vertex color darkening at the bottom, fading to none at the top
+    generated_ao: f32,
     density: f32,
     generated_type: GenType,
     
+    // Distance where each LOD hands over to the next. The last LOD ends at visibility_range
+    lod_distances: PackedFloat32Array,
+    // Adds a camera facing card showing the thumbnail as the last LOD of single mesh scenes
+    generate_impostor: bool,
+
     packed_scene: Option<Gd<PackedScene>>,
     material_override: Option<Gd<Material>>,
+    // One mesh per LOD, most detailed first
     meshes: Vec<Gd<Mesh>>,
+    impostor_material: Option<Gd<StandardMaterial3D>>,
     thumbnail: Option<Gd<ImageTexture>>,
 }
 
@@ -51,14 +82,19 @@ impl IResource for FastTerrainMeshAsset {
             cast_shadows: ShadowCastingSetting::ON,
             generated_faces: 2,
             generated_size: Vector2::new(1.0, 1.0),
+            generated_subdivisions: 1,
+            generated_ao: 0.5,
             density: 10.0,
             generated_type: GenType::TextureCard,
+            lod_distances: PackedFloat32Array::new(),
+            generate_impostor: false,
             packed_scene: None,
             material_override: None,
             meshes: Vec::new(),
+            impostor_material: None,
             thumbnail: None,
         };
-        instance.set_generated_type(GenType::TextureCard);
+        instance.apply_generated_type(GenType::TextureCard);
         instance
     }
 }
@@ -73,10 +109,15 @@ impl FastTerrainAssetResource for FastTerrainMeshAsset {
         self.cast_shadows = ShadowCastingSetting::ON;
         self.generated_faces = 2;
         self.generated_size = Vector2::new(1.0, 1.0);
+        self.generated_subdivisions = 1;
+        self.generated_ao = 0.5;
         self.density = 10.0;
+        self.lod_distances = PackedFloat32Array::new();
+        self.generate_impostor = false;
+        self.impostor_material = None;
         self.packed_scene = None;
         self.material_override = None;
-        self.set_generated_type(GenType::TextureCard);
+        self.apply_generated_type(GenType::TextureCard);
         self.base_mut().notify_property_list_changed();
     }
 
@@ -113,79 +154,180 @@ impl FastTerrainAssetResourceImpl for FastTerrainMeshAsset {}
 
 #[godot_api]
 impl FastTerrainMeshAsset {
-    fn set_generated_type(&mut self, gen_type: GenType) {
+    #[func]
+    pub fn set_generated_type(&mut self, gen_type: GenType) {
+        self.apply_generated_type(gen_type);
+        self.base_mut().emit_signal("file_changed", &[]);
+        self.base_mut().emit_signal("instancer_setting_changed", &[]);
+    }
+
+    #[func]
+    pub fn get_generated_type(&self) -> GenType {
+        self.generated_type
+    }
+
+    #[func]
+    pub fn set_generated_faces(&mut self, faces: i32) {
+        self.generated_faces = faces.clamp(1, 16);
+        self.update_generated();
+    }
+
+    #[func]
+    pub fn get_generated_faces(&self) -> i32 {
+        self.generated_faces
+    }
+
+    #[func]
+    pub fn set_generated_size(&mut self, size: Vector2) {
+        self.generated_size = Vector2::new(size.x.max(0.01), size.y.max(0.01));
+        self.update_generated();
+    }
+
+    #[func]
+    pub fn get_generated_size(&self) -> Vector2 {
+        self.generated_size
+    }
+
+    #[func]
+    pub fn set_generated_subdivisions(&mut self, subdivisions: i32) {
+        self.generated_subdivisions = subdivisions.clamp(1, 32);
+        self.update_generated();
+    }
+
+    #[func]
+    pub fn get_generated_subdivisions(&self) -> i32 {
+        self.generated_subdivisions
+    }
+
+    #[func]
+    pub fn set_generated_ao(&mut self, ao: f32) {
+        self.generated_ao = ao.clamp(0.0, 1.0);
+        self.update_generated();
+    }
+
+    #[func]
+    pub fn get_generated_ao(&self) -> f32 {
+        self.generated_ao
+    }
+
+    // Switches type without signals, for init and resets
+    fn apply_generated_type(&mut self, gen_type: GenType) {
         godot_print!("Setting is_generated: {:?}", gen_type);
-        
-        if (gen_type != GenType::None) && (gen_type != GenType::Max) {
+        self.generated_type = gen_type;
+        if self.is_generated() {
             self.packed_scene = None;
-            self.meshes.clear();
-            godot_print!("Generating card mesh");
-            if let Some(mesh) = self.get_generated_mesh() {
-                self.meshes.push(mesh);
-                self.set_material_override(self.get_material());
-            }
+            self.material_override = None;
+            self.regenerate();
+        }
+    }
+
+    fn is_generated(&self) -> bool {
+        self.generated_type != GenType::None && self.generated_type != GenType::Max
+    }
+
+    fn regenerate(&mut self) {
+        self.meshes.clear();
+        godot_print!("Generating {:?} mesh", self.generated_type);
+        if let Some(mesh) = self.get_generated_mesh() {
+            self.meshes.push(mesh);
+            self.set_material_override(self.get_material());
+        }
+    }
+
+    // Rebuilds the mesh after a generated setting changed, keeping the material
+    fn update_generated(&mut self) {
+        if !self.is_generated() {
+            return;
+        }
+        let material = self.material_override.clone();
+        self.meshes.clear();
+        if let Some(mesh) = self.get_generated_mesh() {
+            self.meshes.push(mesh);
+            self.set_material_override(material.or_else(|| self.get_material()));
+        }
+        self.base_mut().emit_signal("file_changed", &[]);
+        self.base_mut().emit_signal("instancer_setting_changed", &[]);
+    }
+
+    fn get_strips(&self) -> Vec<Strip> {
+        let faces = self.generated_faces.max(1);
+        let half_width = self.generated_size.x * 0.5;
+        let half_turn = |m: i32| m as f32 * std::f32::consts::PI / faces as f32;
+        match self.generated_type {
+            GenType::Billboard => vec![Strip { angle: 0.0, left: -half_width, right: half_width, taper: false }],
+            GenType::Star => (0..faces)
+                .map(|m| Strip { angle: 2.0 * half_turn(m), left: 0.0, right: half_width, taper: false })
+                .collect(),
+            GenType::GrassBlade => (0..faces)
+                .map(|m| Strip { angle: half_turn(m), left: -half_width, right: half_width, taper: true })
+                .collect(),
+            _ => (0..faces)
+                .map(|m| Strip { angle: half_turn(m), left: -half_width, right: half_width, taper: false })
+                .collect(),
         }
-        self.generated_type = gen_type;
     }
 
     fn get_generated_mesh(&self) -> Option<Gd<Mesh>> {
         godot_print!("Regenerating new mesh");
+        Some(Self::build_strips(
+            self.get_strips(),
+            -0.5,
+            self.generated_size.y,
+            self.generated_subdivisions,
+            self.generated_ao,
+        ))
+    }
+
+    // Builds every strip as rows of quads from bottom up to bottom + height. Each strip faces
+    // its own rotated +Z, with the tangent along U, and vertex colors fade from the AO shade
+    // at the bottom to white
+    fn build_strips(strips: Vec<Strip>, bottom: f32, height: f32, rows: i32, ao: f32) -> Gd<Mesh> {
         let mut array_mesh = ArrayMesh::new_gd();
         let mut vertices = PackedVector3Array::new();
         let mut normals = PackedVector3Array::new();
         let mut tangents = PackedFloat32Array::new();
+        let mut colors = PackedColorArray::new();
         let mut uvs = PackedVector2Array::new();
         let mut indices = PackedInt32Array::new();
 
-        let start_pos = Vector2::new(self.generated_size.x * -0.5, -0.5);
-        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let rows = rows.max(1);
         let up = Vector3::UP;
 
-        let mut point = 0;
-        let mut thisrow = point;
-        let mut prevrow = 0;
-
-        for m in 1..=self.generated_faces {
-            let mut z = start_pos.y;
-            let angle = if m > 1 {
-                (m - 1) as f32 * std::f32::consts::PI / self.generated_faces as f32
-            } else {
-                0.0
-            };
-
-            for j in 0..=1 {
-                let mut x = start_pos.x;
-                for i in 0..=1 {
-                    let u = i as f32;
-                    let v = j as f32;
-
-                    let pos = Vector3::new(-x, z, 0.0).rotated(up, angle);
-                    vertices.push(pos);
+        for strip in strips {
+            let normal = Vector3::new(0.0, 0.0, 1.0).rotated(up, strip.angle);
+            let tangent = Vector3::new(1.0, 0.0, 0.0).rotated(up, strip.angle);
+            let center = (strip.left + strip.right) * 0.5;
+            let first = vertices.len() as i32;
+
+            for row in 0..=rows {
+                let v = row as f32 / rows as f32;
+                let y = bottom + v * height;
+                let width_scale = if strip.taper { 1.0 - v } else { 1.0 };
+                let shade = 1.0 - ao * (1.0 - v);
+                // A tapered strip ends in a single tip vertex instead of two coincident ones
+                let tip = strip.taper && row == rows;
+                let columns: &[(f32, f32)] =
+                    if tip { &[(0.5, center)] } else { &[(0.0, strip.left), (1.0, strip.right)] };
+
+                for &(u, x) in columns {
+                    let x = center + (x - center) * width_scale;
+                    vertices.push(Vector3::new(x, y, 0.0).rotated(up, strip.angle));
                     normals.push(normal);
-                    
-                    // ADD_TANGENT macro equivalent
-                    tangents.push(1.0);
-                    tangents.push(0.0);
-                    tangents.push(0.0);
-                    tangents.push(1.0);
-                    
-                    uvs.push(Vector2::new(1.0 - u, 1.0 - v));
-                    
-                    if i > 0 && j > 0 {
-                        indices.push(prevrow + i - 1);
-                        indices.push(prevrow + i);
-                        indices.push(thisrow + i - 1);
-                        indices.push(prevrow + i);
-                        indices.push(thisrow + i);
-                        indices.push(thisrow + i - 1);
+                    tangents.extend_array(&PackedFloat32Array::from(&[tangent.x, tangent.y, tangent.z, 1.0]));
+                    colors.push(Color::from_rgba(shade, shade, shade, 1.0));
+                    uvs.push(Vector2::new(u, 1.0 - v));
+                }
+
+                if row > 0 {
+                    // Clockwise seen from the normal side
+                    let prev_left = first + (row - 1) * 2;
+                    let (prev_right, left, right) = (prev_left + 1, prev_left + 2, prev_left + 3);
+                    if tip {
+                        indices.extend_array(&PackedInt32Array::from(&[left, prev_right, prev_left]));
+                    } else {
+                        indices.extend_array(&PackedInt32Array::from(&[left, prev_right, prev_left, left, right, prev_right]));
                     }
-                    
-                    x += self.generated_size.x;
-                    point += 1;
                 }
-                z += self.generated_size.y;
-                prevrow = thisrow;
-                thisrow = point;
             }
         }
 
@@ -194,11 +336,12 @@ impl FastTerrainMeshAsset {
         arrays.set(ArrayType::VERTEX.ord() as usize, vertices.to_variant().owned_to_arg());
         arrays.set(ArrayType::NORMAL.ord() as usize, normals.to_variant().owned_to_arg());
         arrays.set(ArrayType::TANGENT.ord() as usize, tangents.to_variant().owned_to_arg());
+        arrays.set(ArrayType::COLOR.ord() as usize, colors.to_variant().owned_to_arg());
         arrays.set(ArrayType::TEX_UV.ord() as usize, uvs.to_variant().owned_to_arg());
         arrays.set(ArrayType::INDEX.ord() as usize, indices.to_variant().owned_to_arg());
 
         array_mesh.add_surface_from_arrays(PrimitiveType::TRIANGLES, &arrays);
-        Some(array_mesh.upcast())
+        array_mesh.upcast()
     }
 
     fn get_material(&self) -> Option<Gd<Material>> {
@@ -214,6 +357,10 @@ impl FastTerrainMeshAsset {
             mat.set_distance_fade(DistanceFadeMode::PIXEL_ALPHA);
             mat.set_distance_fade_min_distance(85.0);
             mat.set_distance_fade_max_distance(75.0);
+            if self.generated_type == GenType::Billboard {
+                mat.set_billboard_mode(BillboardMode::FIXED_Y);
+                mat.set_flag(Flags::BILLBOARD_KEEP_SCALE, true);
+            }
             Some(mat.upcast())
         }
     }
@@ -224,6 +371,12 @@ impl FastTerrainMeshAsset {
         self.height_offset = offset.clamp(-50.0, 50.0);
         godot_print!("Setting height offset: {}", self.height_offset);
         self.base_mut().emit_signal("setting_changed", &[]);
+        self.base_mut().emit_signal("instancer_setting_changed", &[]);
+    }
+
+    #[func]
+    pub fn get_height_offset(&self) -> f32 {
+        self.height_offset
     }
 
     #[func]
@@ -240,7 +393,7 @@ impl FastTerrainMeshAsset {
             }
             let node = node.unwrap();
 
-            if self.generated_type != GenType::None && self.generated_type != GenType::Max {
+            if self.is_generated() {
                 // Reset for receiving a scene file
                 self.generated_type = GenType::None;
                 self.material_override = None;
@@ -250,6 +403,8 @@ impl FastTerrainMeshAsset {
             godot_print!("Loaded scene with parent node: {:?}", node);
             let mesh_instances = node.find_children_ex("*").type_("MeshInstance3D").recursive(true).done();
             self.meshes.clear();
+            self.impostor_material = None;
+            let mut lods: Vec<(Option<i32>, Gd<Mesh>)> = Vec::new();
 
             for mesh_instance in mesh_instances.iter_shared() {
                 if let Ok(mi) = mesh_instance.try_cast::<MeshInstance3D>() {
@@ -269,20 +424,29 @@ impl FastTerrainMeshAsset {
                             };
                             mesh.surface_set_material(j, &material);
                         }
-                        self.meshes.push(mesh);
+                        let lod = Self::parse_lod(&mi.get_name().to_string())
+                            .or_else(|| Self::parse_lod(&mesh.get_name().to_string()));
+                        lods.push((lod, mesh));
                     }
                 }
             }
+            self.meshes = Self::sort_lods(lods);
 
             if !self.meshes.is_empty() {
                 let volume = self.meshes[0].get_aabb().volume();
                 self.density = (10.0 / volume).clamp(0.01, 10.0);
+                if self.generate_impostor && self.meshes.len() == 1 {
+                    let lod0 = self.meshes[0].clone();
+                    let impostor = self.create_impostor(&lod0);
+                    self.meshes.push(impostor);
+                }
+                self.reset_lod_distances();
             } else {
                 godot_print!("Error: No MeshInstance3D found in scene file");
             }
             self.base_mut().notify_property_list_changed();
         } else {
-            self.set_generated_type(GenType::TextureCard);
+            self.apply_generated_type(GenType::TextureCard);
             self.density = 10.0;
         }
 
@@ -314,6 +478,11 @@ impl FastTerrainMeshAsset {
         self.visibility_range
     }
 
+    #[func]
+    pub fn get_visibility_margin(&self) -> f32 {
+        self.visibility_margin
+    }
+
     #[func]
     pub fn set_cast_shadows(&mut self, cast_shadows: ShadowCastingSetting) {
         self.cast_shadows = cast_shadows;
@@ -326,6 +495,62 @@ impl FastTerrainMeshAsset {
         self.cast_shadows
     }
 
+    #[func]
+    pub fn get_lod_count(&self) -> i32 {
+        self.meshes.len() as i32
+    }
+
+    #[func]
+    pub fn set_lod_distance(&mut self, lod: i32, distance: f32) {
+        if !(0..MAX_LODS).contains(&lod) {
+            godot_error!("LOD {} out of range 0-{}", lod, MAX_LODS - 1);
+            return;
+        }
+        // LODs without a distance yet get the default one
+        for missing in self.lod_distances.len()..=lod as usize {
+            let default = self.default_lod_distance(missing);
+            self.lod_distances.push(default);
+        }
+        self.lod_distances[lod as usize] = distance.clamp(0.0, 100000.0);
+        godot_print!("Setting LOD{} distance: {}", lod, distance);
+        self.base_mut().emit_signal("instancer_setting_changed", &[]);
+    }
+
+    #[func]
+    pub fn get_lod_distance(&self, lod: i32) -> f32 {
+        self.lod_distances.get(lod as usize).unwrap_or(self.visibility_range)
+    }
+
+    // Begin and end distance a LOD is drawn between. Begins where the previous LOD ends,
+    // and the last LOD ends at visibility_range
+    #[func]
+    pub fn get_lod_range(&self, lod: i32) -> Vector2 {
+        let last = self.get_lod_count() - 1;
+        let begin = if lod <= 0 { 0.0 } else { self.get_lod_distance(lod - 1) };
+        let end = if lod >= last { self.visibility_range } else { self.get_lod_distance(lod) };
+        Vector2::new(begin.min(self.visibility_range), end.min(self.visibility_range))
+    }
+
+    #[func]
+    pub fn set_generate_impostor(&mut self, enabled: bool) {
+        godot_print!("Setting generate impostor: {}", enabled);
+        self.generate_impostor = enabled;
+        if self.packed_scene.is_some() {
+            // Reloading resets the distances, keep them if the impostor didn't change the LOD count
+            let lod_count = self.meshes.len();
+            let lod_distances = self.lod_distances.clone();
+            self.set_scene_file(self.packed_scene.clone());
+            if self.meshes.len() == lod_count {
+                self.lod_distances = lod_distances;
+            }
+        }
+    }
+
+    #[func]
+    pub fn get_generate_impostor(&self) -> bool {
+        self.generate_impostor
+    }
+
     #[func]
     pub fn get_mesh(&self, index: i32) -> Option<Gd<Mesh>> {
         self.meshes.get(index as usize).cloned()
@@ -341,6 +566,78 @@ impl FastTerrainMeshAsset {
         self.thumbnail.clone()
     }
 
+    // Set by FastTerrainAssets::create_mesh_thumbnails. Also shown by the impostor
+    pub fn set_thumbnail(&mut self, thumbnail: Option<Gd<ImageTexture>>) {
+        if let Some(material) = &mut self.impostor_material {
+            let texture = thumbnail.clone().map(|texture| texture.upcast::<Texture2D>());
+            material.set_texture(TextureParam::ALBEDO, texture.as_ref());
+        }
+        self.thumbnail = thumbnail;
+    }
+
+    // LOD index from a _LOD# suffix, in any case, such as Tree_LOD1 or tree_lod1_002
+    fn parse_lod(name: &str) -> Option<i32> {
+        let name = name.to_ascii_lowercase();
+        let start = name.rfind("_lod")? + 4;
+        let digits: String = name[start..].chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse().ok()
+    }
+
+    // Orders meshes by LOD. Without any _LOD# names the first mesh is the only LOD
+    fn sort_lods(mut lods: Vec<(Option<i32>, Gd<Mesh>)>) -> Vec<Gd<Mesh>> {
+        if lods.iter().all(|(lod, _)| lod.is_none()) {
+            if lods.len() > 1 {
+                godot_print!("Found {} meshes without _LOD# names. Using the first", lods.len());
+            }
+            return lods.into_iter().take(1).map(|(_, mesh)| mesh).collect();
+        }
+
+        let unnamed = lods.iter().filter(|(lod, _)| lod.is_none()).count();
+        if unnamed > 0 {
+            godot_print!("Ignoring {} meshes without _LOD# names", unnamed);
+        }
+        lods.retain(|(lod, _)| lod.is_some_and(|lod| lod < MAX_LODS));
+        // Stable, so the first of duplicate LODs is kept
+        lods.sort_by_key(|(lod, _)| *lod);
+        lods.dedup_by_key(|(lod, _)| *lod);
+        for (lod, mesh) in &lods {
+            godot_print!("LOD{}: {}", lod.unwrap_or_default(), mesh.get_name());
+        }
+        lods.into_iter().map(|(_, mesh)| mesh).collect()
+    }
+
+    // Spreads LOD hand over distances evenly up to visibility_range
+    fn reset_lod_distances(&mut self) {
+        let count = self.meshes.len().max(1);
+        self.lod_distances = (0..count).map(|lod| self.default_lod_distance(lod)).collect();
+    }
+
+    fn default_lod_distance(&self, lod: usize) -> f32 {
+        let count = self.meshes.len().max(1);
+        self.visibility_range * (lod + 1) as f32 / count as f32
+    }
+
+    // A card the size of the mesh bounds that faces the camera and shows the thumbnail
+    fn create_impostor(&mut self, mesh: &Gd<Mesh>) -> Gd<Mesh> {
+        godot_print!("Generating impostor LOD");
+        let aabb = mesh.get_aabb();
+        let half_width = aabb.size.x.max(aabb.size.z) * 0.5;
+        let strip = Strip { angle: 0.0, left: -half_width, right: half_width, taper: false };
+        let mut impostor = Self::build_strips(vec![strip], aabb.position.y, aabb.size.y, 1, 0.0);
+
+        let mut material = StandardMaterial3D::new_gd();
+        material.set_transparency(Transparency::ALPHA_SCISSOR);
+        material.set_cull_mode(CullMode::DISABLED);
+        material.set_billboard_mode(BillboardMode::FIXED_Y);
+        material.set_flag(Flags::BILLBOARD_KEEP_SCALE, true);
+        if let Some(thumbnail) = &self.thumbnail {
+            material.set_texture(TextureParam::ALBEDO, &thumbnail.clone().upcast::<Texture2D>());
+        }
+        impostor.surface_set_material(0, &material.clone().upcast::<Material>());
+        self.impostor_material = Some(material);
+        impostor
+    }
+
     fn set_material_override(&mut self, material: Option<Gd<Material>>) {
         godot_print!("{}: Setting material override: {:?}", self.name, material);
         self.material_override = material;
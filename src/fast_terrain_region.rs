@@ -4,12 +4,14 @@ use godot::{
     prelude::*,
 };
 
+use crate::{fast_terrain_data::FastTerrainData, instance_store::InstanceStore};
+
 const COLOR_BLACK: Color = Color::from_rgb(0.0, 0.0, 0.0);
 const COLOR_CONTROL: Color = Color::from_rgba(0.0, 0.0, 0.0, 0.0);
 const COLOR_ROUGHNESS: Color = Color::from_rgb(1.0, 1.0, 1.0);
 const COLOR_NAN: Color = Color::from_rgba(0.0, 0.0, 0.0, 0.0);
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum MapType {
     Height,
@@ -18,6 +20,7 @@ pub enum MapType {
     Max,
 }
 
+// Canonical per-map-type tables, shared by regions, data and import utilities
 impl MapType {
     pub const FORMATS: [Format; 4] = [
         Format::RF,    // Height
@@ -39,6 +42,18 @@ impl MapType {
         COLOR_ROUGHNESS, // Color
         COLOR_NAN,      // Max, unused
     ];
+
+    pub fn format(self) -> Format {
+        Self::FORMATS[self as usize]
+    }
+
+    pub fn type_str(self) -> &'static str {
+        Self::TYPE_STRS[self as usize]
+    }
+
+    pub fn default_color(self) -> Color {
+        Self::COLORS[self as usize]
+    }
 }
 
 #[derive(GodotClass)]
@@ -47,44 +62,45 @@ pub struct FastTerrainRegion {
     #[base]
     base: Base<Resource>,
 
+    #[var(get = get_version, set = set_version, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     version: f32,
+    #[var(get = get_region_size, set = set_region_size, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     region_size: i32,
+    #[var(get = get_vertex_spacing, set = set_vertex_spacing, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     vertex_spacing: f32,
+    #[var(get = get_height_range, set = set_height_range, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     height_range: Vector2,
     location: Vector2i,
 
+    #[var(get = get_height_map, set = set_height_map, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     height_map: Option<Gd<Image>>,
+    #[var(get = get_control_map, set = set_control_map, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     control_map: Option<Gd<Image>>,
+    #[var(get = get_color_map, set = set_color_map, usage_flags = [STORAGE, EDITOR, READ_ONLY])]
     color_map: Option<Gd<Image>>,
+    // Runtime instances. Region files store them packed in instance_data, while files from
+    // before version 0.94 still load through this property
+    #[var(get = get_instances, set = set_instances, usage_flags = [NONE])]
     instances: Dictionary,
+    // Packed data that failed to decode, such as from a newer format. Saved back as is so
+    // the instances aren't erased
+    #[var(get = get_instance_data, set = set_instance_data, usage_flags = [STORAGE])]
+    instance_data: PackedByteArray,
 
     deleted: bool,
     edited: bool,
     modified: bool,
 }
 
-// Inline methods implementation
+#[godot_api]
 impl FastTerrainRegion {
-    const FORMATS: [Format; 3] = [
-        Format::RF,    // Height
-        Format::RGBA8, // Control
-        Format::RGBA8, // Color
-    ];
-
-    const TYPE_STRS: [&'static str; 3] = ["Height", "Control", "Color"];
-
-    const COLORS: [Color; 3] = [
-        Color::from_rgb(0.0, 0.0, 0.0),       // Height
-        Color::from_rgba(0.0, 0.0, 0.0, 0.0), // Control
-        Color::from_rgb(1.0, 1.0, 1.0),       // Color
-    ];
-
-    fn set_version(&mut self, version: f32) {
+    #[func]
+    pub fn set_version(&mut self, version: f32) {
         godot_print!("{:.3}", version);
         self.version = version;
         if self.version < FastTerrainData::CURRENT_VERSION {
             godot_warn!(
-                "Region {} version {:.3} will be updated to {:.3} upon save",
+                "Region {} version {:.3} will be upgraded to {:.3} when loaded",
                 self.base().get_path(),
                 self.version,
                 FastTerrainData::CURRENT_VERSION
@@ -92,6 +108,11 @@ impl FastTerrainRegion {
         }
     }
 
+    #[func]
+    pub fn get_version(&self) -> f32 {
+        self.version
+    }
+
     fn set_map(&mut self, map_type: MapType, image: Option<Gd<Image>>) {
         match map_type {
             MapType::Height => self.set_height_map(image),
@@ -101,7 +122,7 @@ impl FastTerrainRegion {
         }
     }
 
-    fn get_map(&self, map_type: MapType) -> Option<Gd<Image>> {
+    pub fn get_map(&self, map_type: MapType) -> Option<Gd<Image>> {
         match map_type {
             MapType::Height => self.get_height_map(),
             MapType::Control => self.get_control_map(),
@@ -143,7 +164,8 @@ impl FastTerrainRegion {
         maps
     }
 
-    fn set_height_map(&mut self, map: Option<Gd<Image>>) {
+    #[func]
+    pub fn set_height_map(&mut self, map: Option<Gd<Image>>) {
         godot_print!(
             "Setting height map for region: {}",
             if self.location.x != i32::MAX {
@@ -160,7 +182,8 @@ impl FastTerrainRegion {
         self.calc_height_range();
     }
 
-    fn set_control_map(&mut self, map: Option<Gd<Image>>) {
+    #[func]
+    pub fn set_control_map(&mut self, map: Option<Gd<Image>>) {
         godot_print!(
             "Setting control map for region: {}",
             if self.location.x != i32::MAX {
@@ -176,7 +199,8 @@ impl FastTerrainRegion {
         self.control_map = self.sanitize_map(MapType::Control, map);
     }
 
-    fn set_color_map(&mut self, map: Option<Gd<Image>>) {
+    #[func]
+    pub fn set_color_map(&mut self, map: Option<Gd<Image>>) {
         godot_print!(
             "Setting color map for region: {}",
             if self.location.x != i32::MAX {
@@ -205,7 +229,7 @@ impl FastTerrainRegion {
         self.color_map = new_map;
     }
 
-    fn sanitize_maps(&mut self) {
+    pub fn sanitize_maps(&mut self) {
         if self.region_size == 0 {
             godot_error!("Set region_size first");
             return;
@@ -216,8 +240,8 @@ impl FastTerrainRegion {
     }
 
     fn sanitize_map(&self, map_type: MapType, map: Option<Gd<Image>>) -> Option<Gd<Image>> {
-        let type_str = Self::TYPE_STRS[map_type as usize];
-        let format = Self::FORMATS[map_type as usize];
+        let type_str = map_type.type_str();
+        let format = map_type.format();
         let mut result = None;
 
         if let Some(input_map) = map {
@@ -229,6 +253,9 @@ impl FastTerrainRegion {
                         input_map.has_mipmaps()
                     );
                     result = Some(input_map);
+                } else if map_type == MapType::Control && input_map.get_format() == Format::RGBA8 {
+                    godot_print!("Migrating RGBA8 {} map to {:?}", type_str, format);
+                    result = Self::migrate_control_map(&input_map);
                 } else {
                     godot_print!(
                         "Provided {} map wrong format: {:?}. Converting copy to: {:?}",
@@ -268,18 +295,33 @@ impl FastTerrainRegion {
                 map_type == MapType::Color
             );
 
-            let new_map = Image::create_from_data(
+            let mut new_map = Image::create_empty(
                 self.region_size,
                 self.region_size,
                 map_type == MapType::Color,
                 format,
-                &PackedByteArray::new(),
-            )
-            .unwrap();
+            )?;
+            new_map.fill(map_type.default_color());
             Some(new_map)
         })
     }
 
+    // Older regions stored control maps as RGBA8 with the packed control bits in each
+    // pixel's four bytes. RF pixels are also four bytes, so reinterpret the data instead
+    // of letting Image::convert quantize it through floats
+    fn migrate_control_map(map: &Gd<Image>) -> Option<Gd<Image>> {
+        let mut source = Image::new_gd();
+        source.copy_from(map);
+        source.clear_mipmaps();
+        Image::create_from_data(
+            source.get_width(),
+            source.get_height(),
+            false,
+            MapType::Control.format(),
+            &source.get_data(),
+        )
+    }
+
     fn validate_map_size(&self, map: &Gd<Image>) -> bool {
         let size = map.get_size();
         if size.x != size.y {
@@ -312,7 +354,8 @@ impl FastTerrainRegion {
         n > 0 && (n & (n - 1)) == 0
     }
 
-    fn set_height_range(&mut self, range: Vector2) {
+    #[func]
+    pub fn set_height_range(&mut self, range: Vector2) {
         godot_print!("{}", range);
         if self.height_range != range {
             if self.height_range != Vector2::ZERO {
@@ -322,7 +365,13 @@ impl FastTerrainRegion {
         }
     }
 
-    fn calc_height_range(&mut self) {
+    #[func]
+    pub fn get_height_range(&self) -> Vector2 {
+        self.height_range
+    }
+
+    #[func]
+    pub fn calc_height_range(&mut self) {
         if let Some(height_map) = &self.height_map {
             let range = self.get_min_max(height_map);
             if self.height_range != range {
@@ -358,7 +407,8 @@ impl FastTerrainRegion {
         Vector2::new(min, max)
     }
 
-    fn set_region_size(&mut self, size: i32) {
+    #[func]
+    pub fn set_region_size(&mut self, size: i32) {
         if size != self.region_size {
             godot_print!("Setting region size: {}", size);
             self.region_size = size;
@@ -366,64 +416,135 @@ impl FastTerrainRegion {
         }
     }
 
-    fn get_region_size(&self) -> i32 {
+    #[func]
+    pub fn get_region_size(&self) -> i32 {
         self.region_size
     }
 
-    fn set_vertex_spacing(&mut self, spacing: f32) {
+    #[func]
+    pub fn set_vertex_spacing(&mut self, spacing: f32) {
         self.vertex_spacing = spacing;
     }
 
-    fn get_vertex_spacing(&self) -> f32 {
+    #[func]
+    pub fn get_vertex_spacing(&self) -> f32 {
         self.vertex_spacing
     }
 
-    fn set_location(&mut self, location: Vector2i) {
+    #[func]
+    pub fn set_location(&mut self, location: Vector2i) {
         godot_print!("Set location: {}", location);
         self.location = location;
     }
 
-    fn get_location(&self) -> Vector2i {
+    #[func]
+    pub fn get_location(&self) -> Vector2i {
         self.location
     }
 
-    fn set_modified(&mut self, modified: bool) {
+    #[func]
+    pub fn set_modified(&mut self, modified: bool) {
         self.modified = modified;
     }
 
-    fn is_modified(&self) -> bool {
+    #[func]
+    pub fn is_modified(&self) -> bool {
         self.modified
     }
 
-    fn set_edited(&mut self, edited: bool) {
+    #[func]
+    pub fn set_edited(&mut self, edited: bool) {
         self.edited = edited;
     }
 
-    fn is_edited(&self) -> bool {
+    #[func]
+    pub fn is_edited(&self) -> bool {
         self.edited
     }
 
-    fn set_deleted(&mut self, deleted: bool) {
+    #[func]
+    pub fn set_deleted(&mut self, deleted: bool) {
         self.deleted = deleted;
     }
 
-    fn is_deleted(&self) -> bool {
+    #[func]
+    pub fn is_deleted(&self) -> bool {
         self.deleted
     }
 
-    fn get_height_map(&self) -> Option<Gd<Image>> {
+    #[func]
+    pub fn get_height_map(&self) -> Option<Gd<Image>> {
         self.height_map.clone()
     }
 
-    fn get_control_map(&self) -> Option<Gd<Image>> {
+    #[func]
+    pub fn get_control_map(&self) -> Option<Gd<Image>> {
         self.control_map.clone()
     }
 
-    fn get_color_map(&self) -> Option<Gd<Image>> {
+    #[func]
+    pub fn get_color_map(&self) -> Option<Gd<Image>> {
         self.color_map.clone()
     }
 
-    fn save(&mut self, path: GString, sixteen_bit: bool) -> Error {
+    #[func]
+    pub fn set_instances(&mut self, instances: Dictionary) {
+        if self.has_undecoded_instances() && !instances.is_empty() {
+            godot_error!(
+                "Region {} holds instance data that can't be read. Not replacing it",
+                self.location
+            );
+            return;
+        }
+        self.instances = instances;
+    }
+
+    // Packed instance data kept as is because it failed to decode. Instances of the region
+    // can't be edited until it is cleared or read by a newer version
+    #[func]
+    pub fn has_undecoded_instances(&self) -> bool {
+        !self.instance_data.is_empty()
+    }
+
+    #[func]
+    pub fn get_instances(&self) -> Dictionary {
+        self.instances.clone()
+    }
+
+    // Packs the instances with positions quantized over the region. Read by the resource
+    // saver
+    #[func]
+    pub fn get_instance_data(&self) -> PackedByteArray {
+        if self.has_undecoded_instances() || self.instances.is_empty() {
+            return self.instance_data.clone();
+        }
+        let extent = self.region_size as f32 * self.vertex_spacing;
+        let origin = if self.location.x == i32::MAX {
+            Vector2::ZERO
+        } else {
+            Vector2::new(self.location.x as f32, self.location.y as f32) * extent
+        };
+        InstanceStore::from_dictionary(&self.instances).encode(origin, extent)
+    }
+
+    #[func]
+    pub fn set_instance_data(&mut self, data: PackedByteArray) {
+        self.instances = Dictionary::new();
+        self.instance_data = PackedByteArray::new();
+        if data.is_empty() {
+            return;
+        }
+        match InstanceStore::decode(&data) {
+            Ok(store) => self.instances = store.to_dictionary(),
+            Err(error) => {
+                godot_error!("Cannot read instances of region {}: {}", self.base().get_path(), error);
+                self.instance_data = data;
+            }
+        }
+    }
+
+    #[func]
+    pub fn save(&mut self, path: GString, sixteen_bit: bool) -> Error {
         // Check if region is properly set up
         if self.location.x == i32::MAX {
             godot_error!(
@@ -479,11 +600,7 @@ impl FastTerrainRegion {
                 self.height_map = Some(rh_map);
 
                 // Save with compression
-                let save_result = ResourceSaver::singleton()
-                    .save_ex(&self.base().cast::<Resource>())
-                    .path(&self.base().get_path())
-                    .flags(SaverFlags::COMPRESS)
-                    .done();
+                let save_result = self.save_resource();
 
                 // Restore original height map
                 let mut restored_map = Image::new_gd();
@@ -497,11 +614,7 @@ impl FastTerrainRegion {
             }
         } else {
             // Regular save with compression
-            ResourceSaver::singleton()
-                .save_ex(&self.base().cast::<Resource>())
-                .path(&self.base().get_path())
-                .flags(SaverFlags::COMPRESS)
-                .done()
+            self.save_resource()
         };
 
         match result {
@@ -518,6 +631,19 @@ impl FastTerrainRegion {
         result
     }
 
+    fn save_resource(&mut self) -> Error {
+        let resource = self.to_gd().upcast::<Resource>();
+        let path = self.base().get_path();
+        // The saver reads our properties back, so release the binding while it runs
+        let _guard = self.base_mut();
+        ResourceSaver::singleton()
+            .save_ex(&resource)
+            .path(&path)
+            .flags(SaverFlags::COMPRESS)
+            .done()
+    }
+
+    #[func]
     pub fn update_height(&mut self, height: f32) {
         if height < self.height_range.x {
             self.height_range.x = height;
@@ -528,6 +654,7 @@ impl FastTerrainRegion {
         }
     }
 
+    #[func]
     pub fn update_heights(&mut self, low_high: Vector2) {
         if low_high.x < self.height_range.x {
             self.height_range.x = low_high.x;
@@ -538,31 +665,7 @@ impl FastTerrainRegion {
             self.modified = true;
         }
     }
-}
-
-#[godot_api]
-impl IResource for FastTerrainRegion {
-    fn init(base: Base<Resource>) -> Self {
-        Self {
-            base,
-            version: 0.8, // Set to first version to ensure we always upgrade this
-            region_size: 0,
-            vertex_spacing: 1.0,
-            height_range: Vector2::ZERO,
-            location: Vector2i::new(i32::MAX, i32::MAX),
-            height_map: None,
-            control_map: None,
-            color_map: None,
-            instances: Dictionary::new(),
-            deleted: false,
-            edited: false,
-            modified: false,
-        }
-    }
-}
 
-#[godot_api]
-impl FastTerrainRegion {
     #[signal]
     fn modified_changed();
 
@@ -652,5 +755,175 @@ impl FastTerrainRegion {
         if data.contains_key("instances") {
             self.instances = data.get("instances").unwrap().to::<Dictionary>();
         }
+
+        // Bring maps from older data, such as RGBA8 control maps, to the current formats
+        let has_maps = ["height_map", "control_map", "color_map"]
+            .iter()
+            .any(|key| data.contains_key(*key));
+        if has_maps && self.region_size > 0 {
+            self.sanitize_maps();
+        }
+    }
+}
+
+#[godot_api]
+impl IResource for FastTerrainRegion {
+    fn init(base: Base<Resource>) -> Self {
+        Self {
+            base,
+            version: 0.8, // Set to first version to ensure we always upgrade this
+            region_size: 0,
+            vertex_spacing: 1.0,
+            height_range: Vector2::ZERO,
+            location: Vector2i::new(i32::MAX, i32::MAX),
+            height_map: None,
+            control_map: None,
+            color_map: None,
+            instances: Dictionary::new(),
+            instance_data: PackedByteArray::new(),
+            deleted: false,
+            edited: false,
+            modified: false,
+        }
+    }
+}
+
+// A step in the region upgrade pipeline. Steps run in order on regions older than their
+// version, and return a line for each change they made
+struct RegionMigration {
+    version: f32,
+    name: &'static str,
+    apply: fn(&mut FastTerrainRegion) -> Vec<String>,
+}
+
+const REGION_MIGRATIONS: [RegionMigration; 4] = [
+    RegionMigration {
+        version: 0.9,
+        name: "map formats",
+        apply: FastTerrainRegion::migrate_map_formats,
+    },
+    RegionMigration {
+        version: 0.92,
+        name: "height range",
+        apply: FastTerrainRegion::migrate_height_range,
+    },
+    RegionMigration {
+        version: 0.93,
+        name: "instance schema",
+        apply: FastTerrainRegion::migrate_instance_schema,
+    },
+    RegionMigration {
+        version: 0.94,
+        name: "packed instances",
+        apply: FastTerrainRegion::migrate_packed_instances,
+    },
+];
+
+impl FastTerrainRegion {
+    // Brings a freshly loaded region up to FastTerrainData::CURRENT_VERSION
+    pub fn upgrade(&mut self) {
+        if self.version >= FastTerrainData::CURRENT_VERSION {
+            return;
+        }
+
+        godot_print!(
+            "Upgrading region {} from version {:.3} to {:.3}",
+            self.location,
+            self.version,
+            FastTerrainData::CURRENT_VERSION
+        );
+        for migration in REGION_MIGRATIONS.iter() {
+            if self.version >= migration.version {
+                continue;
+            }
+            let changes = (migration.apply)(self);
+            if changes.is_empty() {
+                godot_print!("{:.3} {}: no changes", migration.version, migration.name);
+            }
+            for change in changes {
+                godot_print!("{:.3} {}: {}", migration.version, migration.name, change);
+            }
+            self.version = migration.version;
+        }
+
+        self.version = FastTerrainData::CURRENT_VERSION;
+        self.modified = true;
+    }
+
+    fn migrate_map_formats(&mut self) -> Vec<String> {
+        if self.region_size == 0 {
+            return vec!["Region size unknown, skipping".into()];
+        }
+
+        let before: Vec<Option<Format>> = [MapType::Height, MapType::Control, MapType::Color]
+            .iter()
+            .map(|map_type| self.get_map(*map_type).map(|map| map.get_format()))
+            .collect();
+        self.sanitize_maps();
+
+        [MapType::Height, MapType::Control, MapType::Color]
+            .iter()
+            .zip(before)
+            .filter_map(|(map_type, format)| match format {
+                Some(format) if format == map_type.format() => None,
+                Some(format) => Some(format!(
+                    "Converted {} map from {:?} to {:?}",
+                    map_type.type_str(),
+                    format,
+                    map_type.format()
+                )),
+                None => Some(format!("Created blank {} map", map_type.type_str())),
+            })
+            .collect()
+    }
+
+    fn migrate_height_range(&mut self) -> Vec<String> {
+        let old_range = self.height_range;
+        self.calc_height_range();
+        if self.height_range == old_range {
+            Vec::new()
+        } else {
+            vec![format!("Height range {} -> {}", old_range, self.height_range)]
+        }
+    }
+
+    // Instance cells changed from [transforms, colors] to [transforms, colors, modified]
+    fn migrate_instance_schema(&mut self) -> Vec<String> {
+        let mut updated = 0;
+        for (_, cells) in self.instances.iter_shared() {
+            let Ok(cells) = cells.try_to::<Dictionary>() else {
+                continue;
+            };
+            for (_, cell) in cells.iter_shared() {
+                let Ok(mut cell) = cell.try_to::<VariantArray>() else {
+                    continue;
+                };
+                if cell.len() == 2 {
+                    cell.push(&false.to_variant());
+                    updated += 1;
+                }
+            }
+        }
+
+        if updated == 0 {
+            Vec::new()
+        } else {
+            vec![format!("Added modified flag to {} instance cells", updated)]
+        }
+    }
+
+    // Instances moved from a Dictionary property to packed instance_data. Cells that can't
+    // be packed are dropped here, and the region is saved packed next time
+    fn migrate_packed_instances(&mut self) -> Vec<String> {
+        if self.instances.is_empty() {
+            return Vec::new();
+        }
+        let store = InstanceStore::from_dictionary(&self.instances);
+        self.instances = store.to_dictionary();
+        vec![format!(
+            "Packing {} instances in {} cells",
+            store.instance_count(),
+            store.cells.len()
+        )]
     }
 }
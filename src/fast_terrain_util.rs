@@ -1,6 +1,6 @@
 use godot::{classes::{file_access::ModeFlags, image::{CompressMode, Format, Interpolation, UsedChannels}, resource_loader::CacheMode, Engine, FileAccess, Image, ResourceLoader}, prelude::*};
 
-use crate::{fast_terrain_region::MapType, generated_texture::GeneratedTexture};
+use crate::{control_pixel::ControlPixel, fast_terrain_region::MapType, generated_texture::GeneratedTexture};
 
 #[derive(GodotClass)]
 #[class(base=Object, tool, init)]
@@ -64,7 +64,7 @@ impl FastTerrainUtil {
 
     // Location and filename utilities
     #[func]
-    fn filename_to_location(filename: GString) -> Vector2i {
+    pub fn filename_to_location(filename: GString) -> Vector2i {
         let location_string = filename
             .trim_prefix("terrain3d")
             .trim_suffix(".res")
@@ -86,7 +86,7 @@ impl FastTerrainUtil {
     }
 
     #[func]
-    fn location_to_filename(region_loc: Vector2i) -> GString {
+    pub fn location_to_filename(region_loc: Vector2i) -> GString {
         // Expects a v2i(-1,2) and returns terrain3d-01_02.res
         format!("terrain3d{}.res", Self::location_to_string(region_loc)).into()
     }
@@ -112,6 +112,97 @@ impl FastTerrainUtil {
         format!("{}{}", x_str, y_str).into()
     }
 
+    // Control map utilities. Pixels are passed to GDScript as ints
+    #[func]
+    pub fn as_float(value: u32) -> f32 {
+        f32::from_bits(value)
+    }
+
+    #[func]
+    pub fn as_uint(value: f32) -> u32 {
+        value.to_bits()
+    }
+
+    #[func]
+    fn get_base(pixel: ControlPixel) -> u8 {
+        pixel.base()
+    }
+
+    #[func]
+    fn with_base(pixel: ControlPixel, base: u8) -> ControlPixel {
+        pixel.with_base(base)
+    }
+
+    #[func]
+    fn get_overlay(pixel: ControlPixel) -> u8 {
+        pixel.overlay()
+    }
+
+    #[func]
+    fn with_overlay(pixel: ControlPixel, overlay: u8) -> ControlPixel {
+        pixel.with_overlay(overlay)
+    }
+
+    #[func]
+    fn get_blend(pixel: ControlPixel) -> u8 {
+        pixel.blend()
+    }
+
+    #[func]
+    fn with_blend(pixel: ControlPixel, blend: u8) -> ControlPixel {
+        pixel.with_blend(blend)
+    }
+
+    #[func]
+    fn get_uv_rotation(pixel: ControlPixel) -> u8 {
+        pixel.uv_rotation()
+    }
+
+    #[func]
+    fn with_uv_rotation(pixel: ControlPixel, rotation: u8) -> ControlPixel {
+        pixel.with_uv_rotation(rotation)
+    }
+
+    #[func]
+    fn get_uv_scale(pixel: ControlPixel) -> u8 {
+        pixel.uv_scale()
+    }
+
+    #[func]
+    fn with_uv_scale(pixel: ControlPixel, scale: u8) -> ControlPixel {
+        pixel.with_uv_scale(scale)
+    }
+
+    #[func]
+    fn is_hole(pixel: ControlPixel) -> bool {
+        pixel.is_hole()
+    }
+
+    #[func]
+    fn with_hole(pixel: ControlPixel, hole: bool) -> ControlPixel {
+        pixel.with_hole(hole)
+    }
+
+    #[func]
+    fn is_nav(pixel: ControlPixel) -> bool {
+        pixel.is_nav()
+    }
+
+    #[func]
+    fn with_nav(pixel: ControlPixel, nav: bool) -> ControlPixel {
+        pixel.with_nav(nav)
+    }
+
+    #[func]
+    fn is_auto(pixel: ControlPixel) -> bool {
+        pixel.is_auto()
+    }
+
+    #[func]
+    fn with_auto(pixel: ControlPixel, auto: bool) -> ControlPixel {
+        pixel.with_auto(auto)
+    }
+
     // Image utilities
     #[func]
     fn black_to_alpha(image: Gd<Image>) -> Option<Gd<Image>> {
@@ -156,6 +247,67 @@ impl FastTerrainUtil {
         }
     }
 
+    // Rebuilds only the mipmap texels covering rect, a level 0 pixel rect. Box filtered
+    // like Image::generate_mipmaps, for 8 bit and 32 bit float formats. Image can't write a
+    // single mipmap level, so the buffer still goes back whole with set_data. FastTerrainData
+    // batches painted rects so that happens once per map per frame
+    #[func]
+    pub fn update_mipmaps(mut image: Gd<Image>, rect: Rect2i) {
+        if !image.has_mipmaps() {
+            return;
+        }
+        let format = image.get_format();
+        let Some((channels, channel_bytes)) = Self::texel_layout(format) else {
+            godot_error!("Cannot update mipmaps of image format {:?}", format);
+            return;
+        };
+
+        let size = image.get_size();
+        let Some(mut level_rect) = rect.intersection(Rect2i::new(Vector2i::ZERO, size)) else {
+            return;
+        };
+
+        let mut data = image.get_data();
+        let bytes = data.as_mut_slice();
+        let mut src_size = size;
+        for level in 1..=image.get_mipmap_count() {
+            let src_offset = image.get_mipmap_offset(level - 1) as usize;
+            let dst_offset = image.get_mipmap_offset(level) as usize;
+            let dst_size = Vector2i::new((src_size.x / 2).max(1), (src_size.y / 2).max(1));
+            let start = level_rect.position / 2;
+            let end = ((level_rect.end() + Vector2i::ONE) / 2).clamp(Vector2i::ONE, dst_size);
+            let channel_at = |offset: usize, width: i32, x: i32, y: i32, channel: usize| {
+                offset + ((y * width + x) as usize * channels + channel) * channel_bytes
+            };
+
+            for y in start.y..end.y {
+                for x in start.x..end.x {
+                    for channel in 0..channels {
+                        let sources = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(sx, sy)| {
+                            let src_x = (x * 2 + sx).min(src_size.x - 1);
+                            let src_y = (y * 2 + sy).min(src_size.y - 1);
+                            channel_at(src_offset, src_size.x, src_x, src_y, channel)
+                        });
+                        let dst = channel_at(dst_offset, dst_size.x, x, y, channel);
+                        if channel_bytes == 4 {
+                            let read = |i: usize| f32::from_ne_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+                            let average = sources.iter().map(|i| read(*i)).sum::<f32>() * 0.25;
+                            bytes[dst..dst + 4].copy_from_slice(&average.to_ne_bytes());
+                        } else {
+                            let sum: u32 = sources.iter().map(|i| bytes[*i] as u32).sum();
+                            bytes[dst] = ((sum + 2) / 4) as u8;
+                        }
+                    }
+                }
+            }
+
+            level_rect = Rect2i::from_corners(start, end);
+            src_size = dst_size;
+        }
+
+        image.set_data(size.x, size.y, true, format, &data);
+    }
+
     #[func]
     fn get_thumbnail(image: Gd<Image>, size: Vector2i) -> Option<Gd<Image>> {
         if image.is_empty() {
@@ -197,7 +349,7 @@ impl FastTerrainUtil {
     }
 
     #[func]
-    fn get_filled_image(size: Vector2i, color: Color, create_mipmaps: bool, format: Format) -> Option<Gd<Image>> {
+    pub fn get_filled_image(size: Vector2i, color: Color, create_mipmaps: bool, format: Format) -> Option<Gd<Image>> {
         let format = if format.ord() < Format::MAX.ord() { format } else { Format::DXT5 };
 
         let (compression_format, channels, format, compress, fill_image) = if format.ord() >= Format::DXT1.ord() {
@@ -305,7 +457,7 @@ impl FastTerrainUtil {
                 r16_size
             };
 
-            let mut img = Image::create_empty(r16_size.x, r16_size.y, false, MapType::FORMATS[MapType::Height as usize])?;
+            let mut img = Image::create_empty(r16_size.x, r16_size.y, false, MapType::Height.format())?;
             
             for y in 0..r16_size.y {
                 for x in 0..r16_size.x {
@@ -448,89 +600,22 @@ impl FastTerrainUtil {
         min_max
     }
 
-    // Add remaining utility functions...
-}
-
-// Control map handling functions
-impl FastTerrainUtil {
-    // Bit manipulation helpers
-    fn as_float(value: u32) -> f32 {
-        f32::from_bits(value)
-    }
-
-    fn as_uint(value: f32) -> u32 {
-        value.to_bits()
-    }
-
-    // Base texture functions
-    fn get_base(pixel: u32) -> u8 {
-        ((pixel >> 27) & 0x1F) as u8
-    }
-
-    fn enc_base(base: u8) -> u32 {
-        ((base & 0x1F) as u32) << 27
-    }
-
-    // Overlay functions
-    fn get_overlay(pixel: u32) -> u8 {
-        ((pixel >> 22) & 0x1F) as u8
-    }
-
-    fn enc_overlay(over: u8) -> u32 {
-        ((over & 0x1F) as u32) << 22
-    }
-
-    // Blend functions
-    fn get_blend(pixel: u32) -> u8 {
-        ((pixel >> 14) & 0xFF) as u8
-    }
-
-    fn enc_blend(blend: u8) -> u32 {
-        ((blend & 0xFF) as u32) << 14
-    }
-
-    // UV rotation functions
-    fn get_uv_rotation(pixel: u32) -> u8 {
-        ((pixel >> 10) & 0xF) as u8
-    }
-
-    fn enc_uv_rotation(rotation: u8) -> u32 {
-        ((rotation & 0xF) as u32) << 10
-    }
-
-    // UV scale functions
-    fn get_uv_scale(pixel: u32) -> u8 {
-        ((pixel >> 7) & 0x7) as u8
-    }
-
-    fn enc_uv_scale(scale: u8) -> u32 {
-        ((scale & 0x7) as u32) << 7
-    }
-
-    // Flag functions
-    fn is_hole(pixel: u32) -> bool {
-        ((pixel >> 2) & 0x1) == 1
-    }
-
-    fn enc_hole(hole: bool) -> u32 {
-        ((hole as u32) & 0x1) << 2
-    }
-
-    fn is_nav(pixel: u32) -> bool {
-        ((pixel >> 1) & 0x1) == 1
-    }
-
-    fn enc_nav(nav: bool) -> u32 {
-        ((nav as u32) & 0x1) << 1
-    }
-
-    fn is_auto(pixel: u32) -> bool {
-        (pixel & 0x1) == 1
+    // Channels per texel and bytes per channel of the formats update_mipmaps can filter
+    fn texel_layout(format: Format) -> Option<(usize, usize)> {
+        match format {
+            Format::L8 | Format::R8 => Some((1, 1)),
+            Format::LA8 | Format::RG8 => Some((2, 1)),
+            Format::RGB8 => Some((3, 1)),
+            Format::RGBA8 => Some((4, 1)),
+            Format::RF => Some((1, 4)),
+            Format::RGF => Some((2, 4)),
+            Format::RGBF => Some((3, 4)),
+            Format::RGBAF => Some((4, 4)),
+            _ => None,
+        }
     }
 
-    fn enc_auto(auto: bool) -> u32 {
-        (auto as u32) & 0x1
-    }
+    // Add remaining utility functions...
 }
 
 // Math utilities
@@ -563,7 +648,7 @@ impl FastTerrainUtil {
         }
     }
 
-    fn bilerp(v00: f32, v01: f32, v10: f32, v11: f32, pos00: Vector2, pos11: Vector2, pos: Vector2) -> f32 {
+    pub fn bilerp(v00: f32, v01: f32, v10: f32, v11: f32, pos00: Vector2, pos11: Vector2, pos: Vector2) -> f32 {
         let x2x1 = pos11.x - pos00.x;
         let y2y1 = pos11.y - pos00.y;
         let x2x = pos11.x - pos.x;
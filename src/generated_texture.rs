@@ -1,13 +1,20 @@
+use std::collections::BTreeSet;
+
 use godot::{
     classes::{rendering_server::TextureLayeredType, Image, RenderingServer},
     prelude::*,
 };
 
+// Reference counted, so the texture array is freed with the last owner
 #[derive(GodotClass)]
-#[class(no_init)]
+#[class(no_init, base=RefCounted)]
 pub struct GeneratedTexture {
     rid: Rid,
     image: Option<Gd<Image>>,
+    // Source images of a Texture2DArray, one per layer
+    layers: Array<Gd<Image>>,
+    // Layers changed since the last flush
+    dirty_layers: BTreeSet<i32>,
     dirty: bool,
 }
 
@@ -28,19 +35,26 @@ impl GeneratedTexture {
         self.rid
     }
 
+    #[func]
+    pub fn get_layer_count(&self) -> i32 {
+        self.layers.len() as i32
+    }
+
     #[func]
     pub fn clear(&mut self) {
         if self.rid.is_valid() {
             godot_print!("GeneratedTexture freeing {}", self.rid);
             RenderingServer::singleton().free_rid(self.rid);
         }
-        
+
         if let Some(image) = self.image.take() {
             godot_print!("GeneratedTexture unref image {:?}", image);
             // Image is automatically dropped here
         }
-        
+
         self.rid = Rid::new(0);
+        self.layers.clear();
+        self.dirty_layers.clear();
         self.dirty = true;
     }
 
@@ -48,7 +62,7 @@ impl GeneratedTexture {
     pub fn create_from_layers(&mut self, layers: Array<Gd<Image>>) -> Rid {
         if !layers.is_empty() {
             godot_print!("RenderingServer creating Texture2DArray, layers size: {}", layers.len());
-            
+
             for (i, img) in layers.iter_shared().enumerate() {
                 godot_print!(
                     "{}: {:?}, empty: {}, size: {:?}, format: {:?}",
@@ -60,10 +74,16 @@ impl GeneratedTexture {
                 );
             }
 
-            self.rid = RenderingServer::singleton().texture_2d_layered_create(
-                &layers,
-                TextureLayeredType::LAYERED_2D_ARRAY,
-            );
+            let mut rendering_server = RenderingServer::singleton();
+            let rid = rendering_server.texture_2d_layered_create(&layers, TextureLayeredType::LAYERED_2D_ARRAY);
+            if self.rid.is_valid() {
+                // Keeps the RID materials already hold. The new texture is freed by the swap
+                rendering_server.texture_replace(self.rid, rid);
+            } else {
+                self.rid = rid;
+            }
+            self.layers = layers;
+            self.dirty_layers.clear();
             self.dirty = false;
         } else {
             self.clear();
@@ -71,10 +91,61 @@ impl GeneratedTexture {
         self.rid
     }
 
+    // Rebuilds the array only when the layer count, size or format changed. Otherwise
+    // layers whose image was swapped are marked dirty and uploaded on the next flush
+    #[func]
+    pub fn set_layers(&mut self, layers: Array<Gd<Image>>) -> Rid {
+        if !self.rid.is_valid() || !self.is_compatible(&layers) {
+            return self.create_from_layers(layers);
+        }
+
+        let old_layers = std::mem::replace(&mut self.layers, layers.clone());
+        for (layer, (old, new)) in old_layers.iter_shared().zip(layers.iter_shared()).enumerate() {
+            if old != new {
+                self.add_dirty_layer(layer as i32);
+            }
+        }
+        self.rid
+    }
+
+    // Queues a changed layer for the next flush
+    #[func]
+    pub fn add_dirty_layer(&mut self, layer: i32) {
+        if layer < 0 || layer >= self.layers.len() as i32 {
+            godot_error!("Layer {} out of range 0-{}", layer, self.layers.len() as i32 - 1);
+            return;
+        }
+        self.dirty_layers.insert(layer);
+    }
+
+    #[func]
+    pub fn has_pending_updates(&self) -> bool {
+        !self.dirty_layers.is_empty()
+    }
+
+    // Uploads every dirty layer in full, once. Call at most once per frame. RenderingServer
+    // has no partial update for texture arrays, so a small edit still costs a whole layer,
+    // but edits from many brush dabs share one upload. Returns the number of layers uploaded
+    #[func]
+    pub fn flush(&mut self) -> i32 {
+        if !self.rid.is_valid() || self.dirty_layers.is_empty() {
+            return 0;
+        }
+
+        let mut rendering_server = RenderingServer::singleton();
+        let dirty_layers = std::mem::take(&mut self.dirty_layers);
+        for layer in &dirty_layers {
+            if let Some(image) = self.layers.get(*layer as usize) {
+                rendering_server.texture_2d_update(self.rid, &image, *layer);
+            }
+        }
+        dirty_layers.len() as i32
+    }
+
     #[func]
     pub fn update(&mut self, image: Gd<Image>, layer: i32) {
-        godot_print!("RenderingServer updating Texture2DArray at index: {}", layer);
         RenderingServer::singleton().texture_2d_update(self.rid, &image, layer);
+        self.dirty_layers.remove(&layer);
     }
 
     #[func]
@@ -86,3 +157,36 @@ impl GeneratedTexture {
         self.rid
     }
 }
+
+impl GeneratedTexture {
+    pub fn new() -> Gd<Self> {
+        Gd::from_object(Self {
+            rid: Rid::new(0),
+            image: None,
+            layers: Array::new(),
+            dirty_layers: BTreeSet::new(),
+            dirty: true,
+        })
+    }
+
+    fn is_compatible(&self, layers: &Array<Gd<Image>>) -> bool {
+        if layers.len() != self.layers.len() {
+            return false;
+        }
+        let (Some(old), Some(new)) = (self.layers.get(0), layers.get(0)) else {
+            return false;
+        };
+        layers
+            .iter_shared()
+            .all(|image| image.get_size() == old.get_size() && image.get_format() == old.get_format())
+            && new.get_mipmap_count() == old.get_mipmap_count()
+    }
+}
+
+impl Drop for GeneratedTexture {
+    fn drop(&mut self) {
+        if self.rid.is_valid() {
+            RenderingServer::singleton().free_rid(self.rid);
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+
+use godot::prelude::*;
+
+// Instances of one mesh id in one cell
+#[derive(Clone, Default, Debug)]
+pub struct InstanceCell {
+    pub transforms: Vec<Transform3D>,
+    pub colors: Vec<Color>,
+    pub modified: bool,
+}
+
+// Typed form of the instances of one region, keyed by (mesh id, cell x, cell y). Converts
+// to and from the region dictionary, {mesh_id: {cell: [Array[Transform3D], PackedColorArray,
+// modified]}}, and to a packed byte layout for region files
+//
+// Packed layout, little endian:
+//   header    magic "FTIS" | format version u8 | origin x f32 | origin z f32 | extent f32 | cells u32
+//   cell      mesh id i32 | cell x i32 | cell y i32 | modified u8 | instances u32
+//             | height min f32 | height max f32 | scale min f32 | scale max f32
+//   instance  position 3 x u16 | rotation 4 x i16 | scale 3 x u16 | color 4 x u8
+//
+// Positions are quantized over the region, x and z from origin to origin + extent and y over
+// the cell height range. Scale is quantized over the cell scale range. Shear, mirroring and
+// colors outside 0 to 1 are not kept
+#[derive(Default, Debug)]
+pub struct InstanceStore {
+    pub cells: BTreeMap<(i32, i32, i32), InstanceCell>,
+}
+
+impl InstanceStore {
+    const MAGIC: &'static [u8; 4] = b"FTIS";
+    // Bump when the packed layout changes, and keep decoding older versions
+    pub const FORMAT_VERSION: u8 = 1;
+    const INSTANCE_BYTES: usize = 24;
+
+    pub fn from_dictionary(instances: &Dictionary) -> Self {
+        let mut store = Self::default();
+        for (mesh_id, cells) in instances.iter_shared() {
+            let (Ok(mesh_id), Ok(cells)) = (mesh_id.try_to::<i32>(), cells.try_to::<Dictionary>()) else {
+                continue;
+            };
+            for (cell, cell_data) in cells.iter_shared() {
+                let (Ok(cell), Ok(cell_data)) = (cell.try_to::<Vector2i>(), cell_data.try_to::<VariantArray>()) else {
+                    continue;
+                };
+                let transforms: Vec<Transform3D> = cell_data
+                    .get(0)
+                    .and_then(|transforms| transforms.try_to::<VariantArray>().ok())
+                    .map(|transforms| {
+                        transforms
+                            .iter_shared()
+                            .filter_map(|transform| transform.try_to::<Transform3D>().ok())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if transforms.is_empty() {
+                    continue;
+                }
+                let mut colors: Vec<Color> = cell_data
+                    .get(1)
+                    .and_then(|colors| colors.try_to::<PackedColorArray>().ok())
+                    .map(|colors| colors.as_slice().to_vec())
+                    .unwrap_or_default();
+                colors.resize(transforms.len(), Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+                let modified = cell_data
+                    .get(2)
+                    .and_then(|modified| modified.try_to::<bool>().ok())
+                    .unwrap_or(false);
+
+                store.cells.insert(
+                    (mesh_id, cell.x, cell.y),
+                    InstanceCell {
+                        transforms,
+                        colors,
+                        modified,
+                    },
+                );
+            }
+        }
+        store
+    }
+
+    pub fn to_dictionary(&self) -> Dictionary {
+        let mut instances = Dictionary::new();
+        for (&(mesh_id, x, y), cell) in &self.cells {
+            let mut cells = instances
+                .get(mesh_id)
+                .and_then(|cells| cells.try_to::<Dictionary>().ok())
+                .unwrap_or_default();
+            let transforms: Array<Transform3D> = cell.transforms.iter().copied().collect();
+            let colors: PackedColorArray = cell.colors.iter().copied().collect();
+            let cell_data: VariantArray = [transforms.to_variant(), colors.to_variant(), cell.modified.to_variant()]
+                .into_iter()
+                .collect();
+            cells.set(Vector2i::new(x, y), cell_data);
+            instances.set(mesh_id, cells);
+        }
+        instances
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.cells.values().map(|cell| cell.transforms.len()).sum()
+    }
+
+    // Packs the store with x and z quantized over the square from origin, in global xz, to
+    // origin + extent. Pass the region bounds
+    pub fn encode(&self, origin: Vector2, extent: f32) -> PackedByteArray {
+        PackedByteArray::from(self.encode_bytes(origin, extent).as_slice())
+    }
+
+    pub fn decode(bytes: &PackedByteArray) -> Result<Self, String> {
+        Self::decode_bytes(bytes.as_slice())
+    }
+
+    fn encode_bytes(&self, origin: Vector2, extent: f32) -> Vec<u8> {
+        let extent = extent.max(f32::EPSILON);
+        let mut bytes = Vec::with_capacity(25 + self.cells.len() * 29 + self.instance_count() * Self::INSTANCE_BYTES);
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.push(Self::FORMAT_VERSION);
+        bytes.extend_from_slice(&origin.x.to_le_bytes());
+        bytes.extend_from_slice(&origin.y.to_le_bytes());
+        bytes.extend_from_slice(&extent.to_le_bytes());
+        bytes.extend_from_slice(&(self.cells.len() as u32).to_le_bytes());
+
+        for (&(mesh_id, x, y), cell) in &self.cells {
+            let (height_min, height_max) = Self::range(cell.transforms.iter().map(|transform| transform.origin.y));
+            let (scale_min, scale_max) = Self::range(
+                cell.transforms
+                    .iter()
+                    .flat_map(|transform| transform.basis.scale().abs().to_array()),
+            );
+
+            bytes.extend_from_slice(&mesh_id.to_le_bytes());
+            bytes.extend_from_slice(&x.to_le_bytes());
+            bytes.extend_from_slice(&y.to_le_bytes());
+            bytes.push(cell.modified as u8);
+            bytes.extend_from_slice(&(cell.transforms.len() as u32).to_le_bytes());
+            for value in [height_min, height_max, scale_min, scale_max] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+
+            for (transform, color) in cell.transforms.iter().zip(cell.colors.iter()) {
+                let position = transform.origin;
+                let scale = transform.basis.scale().abs();
+                let quantized = [
+                    Self::quantize(position.x, origin.x, origin.x + extent),
+                    Self::quantize(position.y, height_min, height_max),
+                    Self::quantize(position.z, origin.y, origin.y + extent),
+                ];
+                for value in quantized {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                for value in Self::pack_rotation(&transform.basis, scale) {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                for axis in scale.to_array() {
+                    bytes.extend_from_slice(&Self::quantize(axis, scale_min, scale_max).to_le_bytes());
+                }
+                bytes.extend_from_slice(&[color.r8(), color.g8(), color.b8(), color.a8()]);
+            }
+        }
+        bytes
+    }
+
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4) != Some(Self::MAGIC.as_slice()) {
+            return Err("Not packed instance data".into());
+        }
+        let version = reader.u8().ok_or("Missing format version")?;
+        if version != Self::FORMAT_VERSION {
+            return Err(format!(
+                "Packed instance format version {} is not supported, expected {}",
+                version,
+                Self::FORMAT_VERSION
+            ));
+        }
+        let truncated = || "Packed instance data is truncated".to_string();
+        let origin = Vector2::new(reader.f32().ok_or_else(truncated)?, reader.f32().ok_or_else(truncated)?);
+        let extent = reader.f32().ok_or_else(truncated)?;
+        let cell_count = reader.u32().ok_or_else(truncated)?;
+
+        let mut store = Self::default();
+        for _ in 0..cell_count {
+            let mesh_id = reader.i32().ok_or_else(truncated)?;
+            let x = reader.i32().ok_or_else(truncated)?;
+            let y = reader.i32().ok_or_else(truncated)?;
+            let modified = reader.u8().ok_or_else(truncated)? != 0;
+            let count = reader.u32().ok_or_else(truncated)? as usize;
+            let mut ranges = [0.0; 4];
+            for value in &mut ranges {
+                *value = reader.f32().ok_or_else(truncated)?;
+            }
+            let [height_min, height_max, scale_min, scale_max] = ranges;
+            if reader.remaining() < count * Self::INSTANCE_BYTES {
+                return Err(truncated());
+            }
+
+            let mut cell = InstanceCell {
+                transforms: Vec::with_capacity(count),
+                colors: Vec::with_capacity(count),
+                modified,
+            };
+            for _ in 0..count {
+                let mut values = [0u16; 10];
+                for value in &mut values {
+                    *value = reader.u16().ok_or_else(truncated)?;
+                }
+                let color = reader.take(4).ok_or_else(truncated)?;
+
+                let position = Vector3::new(
+                    Self::dequantize(values[0], origin.x, origin.x + extent),
+                    Self::dequantize(values[1], height_min, height_max),
+                    Self::dequantize(values[2], origin.y, origin.y + extent),
+                );
+                let rotation = Quaternion::new(
+                    values[3] as i16 as f32 / i16::MAX as f32,
+                    values[4] as i16 as f32 / i16::MAX as f32,
+                    values[5] as i16 as f32 / i16::MAX as f32,
+                    values[6] as i16 as f32 / i16::MAX as f32,
+                )
+                .normalized();
+                let scale = Vector3::new(
+                    Self::dequantize(values[7], scale_min, scale_max),
+                    Self::dequantize(values[8], scale_min, scale_max),
+                    Self::dequantize(values[9], scale_min, scale_max),
+                );
+                let [a, b, c] = Basis::from_quat(rotation).to_cols();
+                let basis = Basis::from_cols(a * scale.x, b * scale.y, c * scale.z);
+                cell.transforms.push(Transform3D::new(basis, position));
+                cell.colors.push(Color::from_rgba8(color[0], color[1], color[2], color[3]));
+            }
+            store.cells.insert((mesh_id, x, y), cell);
+        }
+        Ok(store)
+    }
+
+    fn range(values: impl Iterator<Item = f32>) -> (f32, f32) {
+        values.fold((f32::MAX, f32::MIN), |(min, max), value| (min.min(value), max.max(value)))
+    }
+
+    fn quantize(value: f32, min: f32, max: f32) -> u16 {
+        if max <= min {
+            return 0;
+        }
+        ((value - min) / (max - min) * u16::MAX as f32).round().clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn dequantize(value: u16, min: f32, max: f32) -> f32 {
+        if max <= min {
+            return min;
+        }
+        min + value as f32 / u16::MAX as f32 * (max - min)
+    }
+
+    // Rotation as a quaternion with w kept positive, as q and -q are the same rotation
+    fn pack_rotation(basis: &Basis, scale: Vector3) -> [i16; 4] {
+        let safe = |axis: f32| if axis > f32::EPSILON { axis } else { 1.0 };
+        let [a, b, c] = basis.to_cols();
+        let rotation = Basis::from_cols(a / safe(scale.x), b / safe(scale.y), c / safe(scale.z));
+        let mut quat = rotation.orthonormalized().to_quat().normalized();
+        if quat.w < 0.0 {
+            quat = -quat;
+        }
+        [quat.x, quat.y, quat.z, quat.w].map(|value| (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16)
+    }
+}
+
+// Reads little endian values off a byte slice
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.position..self.position + count)?;
+        self.position += count;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        self.u32().map(|value| value as i32)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_store() -> InstanceStore {
+        let mut store = InstanceStore::default();
+        let transforms = (0..20)
+            .map(|i| {
+                let i = i as f32;
+                let rotation = Basis::from_axis_angle(Vector3::new(0.3, 1.0, -0.2).normalized(), i * 0.7);
+                // Scaled along the local axes, as shear isn't kept
+                let [x, y, z] = rotation.to_cols();
+                let basis = Basis::from_cols(x * (0.5 + i * 0.1), y * (1.0 + i * 0.05), z * 0.8);
+                Transform3D::new(basis, Vector3::new(10.0 + i * 11.3, -5.0 + i * 2.5, 200.0 - i * 7.9))
+            })
+            .collect::<Vec<_>>();
+        let colors = (0..20).map(|i| Color::from_rgba8(i * 10, 255 - i * 10, 128, 255)).collect();
+        store.cells.insert(
+            (3, 1, 6),
+            InstanceCell {
+                transforms,
+                colors,
+                modified: true,
+            },
+        );
+        store.cells.insert(
+            (0, 0, 0),
+            InstanceCell {
+                transforms: vec![Transform3D::new(Basis::IDENTITY, Vector3::new(1.0, 2.0, 3.0))],
+                colors: vec![Color::from_rgba(1.0, 1.0, 1.0, 1.0)],
+                modified: false,
+            },
+        );
+        store
+    }
+
+    #[test]
+    fn round_trip() {
+        let store = sample_store();
+        let (origin, extent) = (Vector2::new(0.0, 0.0), 256.0);
+        let decoded = InstanceStore::decode_bytes(&store.encode_bytes(origin, extent)).unwrap();
+        assert_eq!(decoded.cells.len(), store.cells.len());
+
+        for (key, cell) in &store.cells {
+            let decoded_cell = &decoded.cells[key];
+            assert_eq!(decoded_cell.modified, cell.modified);
+            assert_eq!(decoded_cell.transforms.len(), cell.transforms.len());
+
+            let (height_min, height_max) = InstanceStore::range(cell.transforms.iter().map(|t| t.origin.y));
+            let (scale_min, scale_max) =
+                InstanceStore::range(cell.transforms.iter().flat_map(|t| t.basis.scale().abs().to_array()));
+            // Half a quantization step, plus float slack
+            let position_tolerance = Vector3::new(extent, height_max - height_min, extent) / u16::MAX as f32 * 0.5 + Vector3::splat(1e-3);
+            let scale_tolerance = (scale_max - scale_min) / u16::MAX as f32 * 0.5 + 1e-3;
+
+            for (original, decoded) in cell.transforms.iter().zip(&decoded_cell.transforms) {
+                let error = (original.origin - decoded.origin).abs();
+                assert!(
+                    error.x <= position_tolerance.x && error.y <= position_tolerance.y && error.z <= position_tolerance.z,
+                    "position {} decoded as {}",
+                    original.origin,
+                    decoded.origin
+                );
+
+                let (original_scale, decoded_scale) = (original.basis.scale().abs(), decoded.basis.scale().abs());
+                assert!(
+                    (original_scale - decoded_scale).abs().to_array().iter().all(|e| *e <= scale_tolerance),
+                    "scale {} decoded as {}",
+                    original_scale,
+                    decoded_scale
+                );
+
+                let [a, b, c] = original.basis.to_cols();
+                let [da, db, dc] = decoded.basis.to_cols();
+                let original_rotation = Basis::from_cols(a / original_scale.x, b / original_scale.y, c / original_scale.z);
+                let decoded_rotation = Basis::from_cols(da / decoded_scale.x, db / decoded_scale.y, dc / decoded_scale.z);
+                let angle = original_rotation.to_quat().angle_to(decoded_rotation.to_quat());
+                assert!(angle < 1e-3, "rotation off by {} radians", angle);
+            }
+            for (original, decoded) in cell.colors.iter().zip(&decoded_cell.colors) {
+                assert_eq!([original.r8(), original.g8(), original.b8(), original.a8()], [decoded.r8(), decoded.g8(), decoded.b8(), decoded.a8()]);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_data() {
+        let bytes = sample_store().encode_bytes(Vector2::ZERO, 256.0);
+        for length in [5, 12, 24, 40, bytes.len() - 1] {
+            let error = InstanceStore::decode_bytes(&bytes[..length]).unwrap_err();
+            assert!(error.contains("truncated"), "{} bytes: {}", length, error);
+        }
+        assert!(InstanceStore::decode_bytes(&bytes[..2]).is_err());
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut bytes = sample_store().encode_bytes(Vector2::ZERO, 256.0);
+        bytes[4] = InstanceStore::FORMAT_VERSION + 1;
+        let error = InstanceStore::decode_bytes(&bytes).unwrap_err();
+        assert!(error.contains("not supported"), "{}", error);
+    }
+}
@@ -1,14 +1,37 @@
+mod clipmap_geometry;
+mod control_pixel;
 mod fast_terrain_assets_resource;
 mod fast_terrain_assets;
+mod fast_terrain_collision;
+mod fast_terrain_data;
+mod fast_terrain_edit;
+mod fast_terrain_editor;
+mod fast_terrain_history;
+mod fast_terrain_instancer;
+mod fast_terrain_material;
 mod fast_terrain_mesh_asset;
 mod fast_terrain_region;
 mod fast_terrain_texture_asset;
 mod fast_terrain_util;
 mod generated_texture;
 mod geoclipmap;
+mod instance_store;
 mod types;
 
-use godot::{classes::RenderingServer, prelude::*};
+use godot::{
+    classes::{notify::Node3DNotification, object::ConnectFlags, RenderingServer},
+    prelude::*,
+};
+
+use crate::{
+    clipmap_geometry::MeshType,
+    fast_terrain_assets::FastTerrainAssets,
+    fast_terrain_collision::{CollisionMode, FastTerrainCollision},
+    fast_terrain_data::FastTerrainData,
+    fast_terrain_instancer::FastTerrainInstancer,
+    fast_terrain_material::FastTerrainMaterial,
+    geoclipmap::{GeoClipMap, NormalMode},
+};
 
 struct FastTerrainExtension;
 
@@ -25,12 +48,54 @@ unsafe impl ExtensionLibrary for FastTerrainExtension {
 }
 
 #[derive(GodotClass)]
-#[class(base=Node3D)]
-struct FastTerrain {
+#[class(tool, base=Node3D)]
+pub struct FastTerrain {
     #[export]
     region_size: RegionSize,
-
+    #[export(range = (0.25, 100.0, or_greater))]
+    #[var(get = get_vertex_spacing, set = set_vertex_spacing)]
+    vertex_spacing: f32,
+    #[export(dir)]
+    #[var(get = get_data_directory, set = set_data_directory)]
     data_directory: GString,
+    #[export(range = (1.0, 10.0))]
+    #[var(get = get_mesh_lods, set = set_mesh_lods)]
+    mesh_lods: i32,
+    #[export(range = (8.0, 64.0))]
+    #[var(get = get_mesh_size, set = set_mesh_size)]
+    mesh_size: i32,
+    // Leave NORMAL and TANGENT out of the clipmap meshes when the shader computes them
+    #[export]
+    #[var(get = get_strip_mesh_normals, set = set_strip_mesh_normals)]
+    strip_mesh_normals: bool,
+    // Depth of the walls hung off each clipmap piece to hide cracks. Zero disables skirts
+    #[export(range = (0.0, 100.0, or_greater))]
+    #[var(get = get_skirt_depth, set = set_skirt_depth)]
+    skirt_depth: f32,
+    // Node the clipmap rings follow. Falls back to the active viewport camera
+    #[export]
+    clipmap_target: Option<Gd<Node3D>>,
+    #[export]
+    #[var(get = get_collision_mode, set = set_collision_mode)]
+    collision_mode: CollisionMode,
+    // Distance from the clipmap target within which regions collide in Radius mode
+    #[export(range = (16.0, 4096.0, or_greater))]
+    #[var(get = get_collision_radius, set = set_collision_radius)]
+    collision_radius: f32,
+    // Created with defaults when unset
+    #[export]
+    #[var(get = get_material, set = set_material)]
+    material: Option<Gd<FastTerrainMaterial>>,
+    #[export]
+    #[var(get = get_assets, set = set_assets)]
+    assets: Option<Gd<FastTerrainAssets>>,
+
+    data: Option<Gd<FastTerrainData>>,
+    instancer: Option<Gd<FastTerrainInstancer>>,
+    meshes: Vec<Vec<Rid>>,
+    clipmap: ClipmapInstances,
+    collision: FastTerrainCollision,
+    target_last_position: Vector2,
     is_inside_world: bool,
     initialized: bool,
     warnings: u8,
@@ -38,7 +103,36 @@ struct FastTerrain {
     base: Base<Node3D>,
 }
 
-#[derive(GodotConvert, Var, Export)]
+struct ClipmapInstances {
+    cross: Rid,
+    tiles: Vec<Rid>,
+    fillers: Vec<Rid>,
+    trims: Vec<Rid>,
+    seams: Vec<Rid>,
+}
+
+impl ClipmapInstances {
+    fn new() -> Self {
+        Self {
+            cross: Rid::Invalid,
+            tiles: Vec::new(),
+            fillers: Vec::new(),
+            trims: Vec::new(),
+            seams: Vec::new(),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = Rid> + '_ {
+        std::iter::once(self.cross)
+            .chain(self.tiles.iter().copied())
+            .chain(self.fillers.iter().copied())
+            .chain(self.trims.iter().copied())
+            .chain(self.seams.iter().copied())
+            .filter(|rid| rid.is_valid())
+    }
+}
+
+#[derive(GodotConvert, Var, Export, Clone, Copy, PartialEq, Debug)]
 #[godot(via = GString)]
 enum RegionSize {
     Size64 = 64,
@@ -54,7 +148,23 @@ impl INode3D for FastTerrain {
     fn init(base: Base<Node3D>) -> Self {
         Self {
             region_size: RegionSize::Size256,
+            vertex_spacing: 1.0,
             data_directory: "".into(),
+            mesh_lods: 7,
+            mesh_size: 48,
+            strip_mesh_normals: false,
+            skirt_depth: 0.0,
+            clipmap_target: None,
+            collision_mode: CollisionMode::Full,
+            collision_radius: 256.0,
+            material: None,
+            assets: None,
+            data: None,
+            instancer: None,
+            meshes: Vec::new(),
+            clipmap: ClipmapInstances::new(),
+            collision: FastTerrainCollision::new(),
+            target_last_position: Vector2::new(f32::MAX, f32::MAX),
             is_inside_world: false,
             initialized: false,
             warnings: 0,
@@ -63,17 +173,631 @@ impl INode3D for FastTerrain {
     }
 
     fn ready(&mut self) {
-        let new_node = RenderingServer::singleton().instance_create();
-        if new_node.is_valid() {
-        // self.base().get_tree().unwrap().get_root().unwrap().add_child(&new_node);
+        self.initialize();
+        self.base_mut().set_process(true);
+    }
+
+    fn process(&mut self, _delta: f64) {
+        if !self.is_inside_world {
+            return;
+        }
+
+        // Painted map layers reach the GPU once per frame however many strokes touched them
+        if let Some(data) = &mut self.data {
+            data.bind_mut().flush_maps();
+        }
+
+        let Some(position) = self.get_target_position() else {
+            return;
+        };
+        let position_2d = Vector2::new(position.x, position.z);
+        if (position_2d - self.target_last_position).length() > 0.2 {
+            self.target_last_position = position_2d;
+            // Maps and collision still follow the target without clipmap meshes
+            if !self.meshes.is_empty() {
+                self.snap(position);
+            }
+            if self.collision_mode == CollisionMode::Radius {
+                self.update_collision();
+            }
+        }
+    }
+
+    fn on_notification(&mut self, what: Node3DNotification) {
+        match what {
+            Node3DNotification::ENTER_WORLD => {
+                self.is_inside_world = true;
+                self.build_meshes(self.mesh_lods, self.mesh_size);
+                self.update_collision();
+                self.setup_instancer();
+            }
+            Node3DNotification::EXIT_WORLD => {
+                self.is_inside_world = false;
+                self.clear_meshes();
+                self.collision.destroy();
+                if let Some(instancer) = &mut self.instancer {
+                    instancer.bind_mut().destroy();
+                }
+            }
+            Node3DNotification::VISIBILITY_CHANGED => {
+                let visible = self.base().is_visible_in_tree();
+                let mut rs = RenderingServer::singleton();
+                for instance in self.clipmap.all() {
+                    rs.instance_set_visible(instance, visible);
+                }
+                if let Some(instancer) = &mut self.instancer {
+                    instancer.bind_mut().set_visible(visible);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[godot_api]
+impl FastTerrain {
+    #[func]
+    pub fn get_data(&self) -> Option<Gd<FastTerrainData>> {
+        self.data.clone()
+    }
+
+    #[func]
+    pub fn get_instancer(&self) -> Option<Gd<FastTerrainInstancer>> {
+        self.instancer.clone()
+    }
+
+    // Rebuilds every instance multimesh from the region data and mesh assets
+    #[func]
+    pub fn update_instancer(&mut self) {
+        if !self.is_inside_world {
+            return;
+        }
+        if let Some(instancer) = &mut self.instancer {
+            instancer.bind_mut().update_mmis();
+        }
+    }
+
+    #[func]
+    pub fn set_data_directory(&mut self, directory: GString) {
+        godot_print!("Setting data directory: {}", directory);
+        if self.data_directory != directory {
+            self.data_directory = directory;
+            if self.initialized {
+                self.load_data();
+            }
+        }
+    }
+
+    #[func]
+    pub fn get_data_directory(&self) -> GString {
+        self.data_directory.clone()
+    }
+
+    #[func]
+    pub fn set_vertex_spacing(&mut self, spacing: f32) {
+        self.vertex_spacing = spacing.clamp(0.25, 100.0);
+        godot_print!("Setting vertex spacing: {}", self.vertex_spacing);
+        if let Some(data) = &mut self.data {
+            data.bind_mut().set_vertex_spacing(self.vertex_spacing);
+        }
+        // Shape transforms depend on the spacing
+        self.collision.clear_shapes();
+        self.update_collision();
+        self.update_material();
+    }
+
+    #[func]
+    pub fn get_vertex_spacing(&self) -> f32 {
+        self.vertex_spacing
+    }
+
+    #[func]
+    pub fn set_mesh_lods(&mut self, lods: i32) {
+        let lods = lods.clamp(1, 10);
+        if self.mesh_lods != lods {
+            godot_print!("Setting mesh levels: {}", lods);
+            self.mesh_lods = lods;
+            self.rebuild_meshes();
+        }
+    }
+
+    #[func]
+    pub fn get_mesh_lods(&self) -> i32 {
+        self.mesh_lods
+    }
+
+    #[func]
+    pub fn set_mesh_size(&mut self, size: i32) {
+        let size = size.clamp(8, 64);
+        if self.mesh_size != size {
+            godot_print!("Setting mesh size: {}", size);
+            self.mesh_size = size;
+            self.rebuild_meshes();
+        }
+    }
+
+    #[func]
+    pub fn get_mesh_size(&self) -> i32 {
+        self.mesh_size
+    }
+
+    #[func]
+    pub fn set_strip_mesh_normals(&mut self, strip: bool) {
+        if self.strip_mesh_normals != strip {
+            godot_print!("Setting strip mesh normals: {}", strip);
+            self.strip_mesh_normals = strip;
+            self.rebuild_meshes();
+        }
+    }
+
+    #[func]
+    pub fn get_strip_mesh_normals(&self) -> bool {
+        self.strip_mesh_normals
+    }
+
+    #[func]
+    pub fn set_skirt_depth(&mut self, depth: f32) {
+        let depth = depth.max(0.0);
+        if self.skirt_depth != depth {
+            godot_print!("Setting skirt depth: {}", depth);
+            self.skirt_depth = depth;
+            self.rebuild_meshes();
+        }
+    }
+
+    #[func]
+    pub fn get_skirt_depth(&self) -> f32 {
+        self.skirt_depth
+    }
+
+    #[func]
+    pub fn set_collision_mode(&mut self, mode: CollisionMode) {
+        if self.collision_mode != mode {
+            godot_print!("Setting collision mode: {:?}", mode);
+            self.collision_mode = mode;
+            self.update_collision();
+        }
+    }
+
+    #[func]
+    pub fn get_collision_mode(&self) -> CollisionMode {
+        self.collision_mode
+    }
+
+    #[func]
+    pub fn set_collision_radius(&mut self, radius: f32) {
+        let radius = radius.max(16.0);
+        if self.collision_radius != radius {
+            godot_print!("Setting collision radius: {}", radius);
+            self.collision_radius = radius;
+            self.update_collision();
+        }
+    }
+
+    #[func]
+    pub fn get_collision_radius(&self) -> f32 {
+        self.collision_radius
+    }
+
+    // Brings the collision shapes in line with the current mode and region map
+    #[func]
+    pub fn update_collision(&mut self) {
+        if !self.is_inside_world {
+            return;
+        }
+        let Some(data) = self.data.clone() else {
+            return;
+        };
+
+        let regions = FastTerrainCollision::active_regions(
+            self.collision_mode,
+            &data.bind(),
+            self.get_target_position(),
+            self.collision_radius,
+        );
+        if regions.is_empty() {
+            self.collision.destroy();
+            return;
+        }
+
+        if !self.collision.is_built() {
+            let Some(world) = self.base().get_world_3d() else {
+                godot_error!("Terrain is not inside a world. Cannot build collision");
+                return;
+            };
+            let instance_id = self.base().instance_id();
+            self.collision.build(world.get_space(), instance_id);
+        }
+        self.collision.sync(&data.bind(), &regions);
+    }
+
+    #[func]
+    pub fn update_collision_area(&mut self, edited_area: Aabb) {
+        if let Some(data) = &self.data {
+            self.collision.update_area(&data.bind(), edited_area);
+        }
+    }
+
+    // Edits can move the height range, so refresh culling bounds along with collision
+    #[func]
+    fn on_maps_edited(&mut self, edited_area: Aabb) {
+        self.update_aabbs();
+        self.update_collision_area(edited_area);
+    }
+
+    // Builds a standalone set of clipmap meshes, one array per MeshType, shaded from
+    // height_source if it is valid. Tiles hold one mesh per position in the 4x4 grid of a
+    // ring, x * 4 + y, as their morph factors differ. Heights are sampled in world space, with mesh vertices
+    // scaled by scale and offset by origin in XZ, matching where the meshes will be placed.
+    // The caller owns the returned RIDs and must free them.
+    #[func]
+    pub fn create_clipmap_meshes(size: i32, lods: i32, height_source: Callable, scale: f32, origin: Vector2) -> VariantArray {
+        let normal_mode = if height_source.is_valid() {
+            NormalMode::Heights {
+                source: &height_source,
+                scale,
+                origin,
+            }
+        } else {
+            NormalMode::Flat
+        };
+        GeoClipMap::generate(size.clamp(8, 64), lods.clamp(1, 10), normal_mode, 0.0)
+            .into_iter()
+            .map(|meshes| meshes.into_iter().collect::<Array<Rid>>().to_variant())
+            .collect()
+    }
+
+    #[func]
+    pub fn set_material(&mut self, material: Option<Gd<FastTerrainMaterial>>) {
+        godot_print!("Setting material: {:?}", material);
+        self.material = material;
+        if self.initialized && self.material.is_none() {
+            self.material = Some(FastTerrainMaterial::new_gd());
         }
-        // self.base().get_tree().unwrap().get_root().unwrap().add_child(
+        self.apply_material();
+        self.update_material();
+    }
+
+    #[func]
+    pub fn get_material(&self) -> Option<Gd<FastTerrainMaterial>> {
+        self.material.clone()
+    }
+
+    #[func]
+    pub fn set_assets(&mut self, assets: Option<Gd<FastTerrainAssets>>) {
+        godot_print!("Setting assets: {:?}", assets);
+        let callable = self.base().callable("update_material");
+        let instancer_callable = self.base().callable("update_instancer");
+        if let Some(old_assets) = &mut self.assets {
+            if old_assets.is_connected("textures_changed", &callable) {
+                old_assets.disconnect("textures_changed", &callable);
+            }
+            if old_assets.is_connected("meshes_changed", &instancer_callable) {
+                old_assets.disconnect("meshes_changed", &instancer_callable);
+            }
+        }
+
+        if let Some(mut assets) = assets.clone() {
+            assets.bind_mut().initialize(self.to_gd());
+            let deferred = ConnectFlags::DEFERRED.ord() as u32;
+            assets.connect_ex("textures_changed", &callable).flags(deferred).done();
+            assets.connect_ex("meshes_changed", &instancer_callable).flags(deferred).done();
+        }
+        self.assets = assets;
+        if let Some(instancer) = &mut self.instancer {
+            instancer.bind_mut().set_assets(self.assets.clone());
+        }
+        self.update_material();
+        self.update_instancer();
+    }
 
+    #[func]
+    pub fn get_assets(&self) -> Option<Gd<FastTerrainAssets>> {
+        self.assets.clone()
+    }
+
+    // Feeds the current region maps and texture arrays to the material
+    #[func]
+    pub fn update_material(&mut self) {
+        let (Some(material), Some(data)) = (&mut self.material, &self.data) else {
+            return;
+        };
+        let assets = self.assets.as_ref().map(|assets| assets.bind());
+        material
+            .bind_mut()
+            .update(&data.bind(), assets.as_deref());
+    }
+
+    #[func]
+    pub fn update_aabbs(&mut self) {
+        if self.meshes.is_empty() {
+            return;
+        }
+
+        let height_range = self
+            .data
+            .as_ref()
+            .map_or(Vector2::ZERO, |data| data.bind().get_height_range());
+        let skirt_depth = self.skirt_depth;
+        let mut rs = RenderingServer::singleton();
+        let mut set_aabb = |instances: &[Rid], mesh: Rid| {
+            let mut aabb = rs.mesh_get_custom_aabb(mesh);
+            aabb.position.y = height_range.x - skirt_depth;
+            aabb.size.y = height_range.y - height_range.x + skirt_depth;
+            for instance in instances {
+                rs.instance_set_custom_aabb(*instance, aabb);
+            }
+        };
+
+        // Variants of a piece only differ in their morph factors, so share one AABB
+        set_aabb(&[self.clipmap.cross], self.meshes[MeshType::Cross as usize][0]);
+        set_aabb(&self.clipmap.tiles, self.meshes[MeshType::Tile as usize][0]);
+        set_aabb(&self.clipmap.fillers, self.meshes[MeshType::Filler as usize][0]);
+        set_aabb(&self.clipmap.trims, self.meshes[MeshType::Trim as usize][0]);
+        set_aabb(&self.clipmap.seams, self.meshes[MeshType::Seam as usize][0]);
+    }
+
+    #[func]
+    pub fn get_height(&self, global_position: Vector3) -> f32 {
+        self.data
+            .as_ref()
+            .map_or(f32::NAN, |data| data.bind().get_height(global_position))
+    }
+
+    #[func]
+    pub fn get_normal(&self, global_position: Vector3) -> Vector3 {
+        self.data.as_ref().map_or(Vector3::new(f32::NAN, f32::NAN, f32::NAN), |data| {
+            data.bind().get_normal(global_position)
+        })
     }
 }
 
 impl FastTerrain {
-    fn build_meshes(&mut self, lods: i8, size: i32) {
+    fn initialize(&mut self) {
+        if self.initialized {
+            return;
+        }
+
+        let mut data = FastTerrainData::new_gd();
+        data.bind_mut().initialize(self.region_size as i32, self.vertex_spacing);
+        // Deferred, as data emits these while the terrain may still be bound
+        let deferred = ConnectFlags::DEFERRED.ord() as u32;
+        data.connect_ex("region_map_changed", &self.base().callable("update_aabbs"))
+            .flags(deferred)
+            .done();
+        data.connect_ex("region_map_changed", &self.base().callable("update_collision"))
+            .flags(deferred)
+            .done();
+        data.connect_ex("maps_edited", &self.base().callable("on_maps_edited"))
+            .flags(deferred)
+            .done();
+        data.connect_ex("region_map_changed", &self.base().callable("update_material"))
+            .flags(deferred)
+            .done();
+        data.connect_ex("region_map_changed", &self.base().callable("update_instancer"))
+            .flags(deferred)
+            .done();
+        let mut instancer = FastTerrainInstancer::new_gd();
+        instancer.bind_mut().set_assets(self.assets.clone());
+        self.instancer = Some(instancer);
+        self.data = Some(data);
+        if self.material.is_none() {
+            self.material = Some(FastTerrainMaterial::new_gd());
+        }
+        self.initialized = true;
+        self.load_data();
+        self.update_aabbs();
+        self.update_material();
+    }
+
+    fn setup_instancer(&mut self) {
+        let Some(world) = self.base().get_world_3d() else {
+            return;
+        };
+        let visible = self.base().is_visible_in_tree();
+        let (Some(instancer), Some(data)) = (&mut self.instancer, &self.data) else {
+            return;
+        };
+        let mut instancer = instancer.bind_mut();
+        instancer.initialize(data.clone(), world.get_scenario());
+        instancer.set_visible(visible);
+        instancer.update_mmis();
+    }
+
+    fn apply_material(&mut self) {
+        let material_rid = match &mut self.material {
+            Some(material) => material.bind_mut().get_material_rid(),
+            None => Rid::Invalid,
+        };
+        let mut rs = RenderingServer::singleton();
+        for instance in self.clipmap.all() {
+            rs.instance_geometry_set_material_override(instance, material_rid);
+        }
+    }
+
+    fn load_data(&mut self) {
+        if self.data_directory.is_empty() {
+            return;
+        }
+        if let Some(data) = &mut self.data {
+            data.bind_mut().load_directory(self.data_directory.clone());
+        }
+    }
+
+    fn get_target_position(&self) -> Option<Vector3> {
+        if let Some(target) = &self.clipmap_target {
+            if target.is_instance_valid() && target.is_inside_tree() {
+                return Some(target.get_global_position());
+            }
+        }
+        let camera = self.base().get_viewport()?.get_camera_3d()?;
+        Some(camera.get_global_position())
+    }
+
+    fn rebuild_meshes(&mut self) {
+        if self.is_inside_world && !self.meshes.is_empty() {
+            self.clear_meshes();
+            self.build_meshes(self.mesh_lods, self.mesh_size);
+        }
+    }
+
+    fn build_meshes(&mut self, lods: i32, size: i32) {
         godot_print!("Building meshes with {} LODs and size {}", lods, size);
+        let Some(world) = self.base().get_world_3d() else {
+            godot_error!("Terrain is not inside a world. Cannot build meshes");
+            return;
+        };
+        let scenario = world.get_scenario();
+
+        self.clear_meshes();
+        let normal_mode = if self.strip_mesh_normals {
+            NormalMode::Strip
+        } else {
+            NormalMode::Flat
+        };
+        self.meshes = GeoClipMap::generate(size, lods, normal_mode, self.skirt_depth);
+
+        let mut rs = RenderingServer::singleton();
+        let mesh = |mesh_type: MeshType, lod: i32, index: usize| {
+            // The innermost level gets its own variant of each ring piece
+            let mesh_type = match (mesh_type, lod) {
+                (MeshType::Tile, 0) => MeshType::TileInner,
+                (MeshType::Filler, 0) => MeshType::FillerInner,
+                (MeshType::Trim, 0) => MeshType::TrimInner,
+                (mesh_type, _) => mesh_type,
+            };
+            self.meshes[mesh_type as usize][index]
+        };
+
+        self.clipmap.cross = rs.instance_create2(mesh(MeshType::Cross, 0, 0), scenario);
+        for lod in 0..lods {
+            for x in 0..4 {
+                for y in 0..4 {
+                    // The center of every level past the first is covered by the level below
+                    if lod != 0 && (x == 1 || x == 2) && (y == 1 || y == 2) {
+                        continue;
+                    }
+                    let tile = rs.instance_create2(mesh(MeshType::Tile, lod, GeoClipMap::tile_index(x, y)), scenario);
+                    self.clipmap.tiles.push(tile);
+                }
+            }
+
+            let filler = rs.instance_create2(mesh(MeshType::Filler, lod, 0), scenario);
+            self.clipmap.fillers.push(filler);
+
+            if lod != lods - 1 {
+                let trim = rs.instance_create2(mesh(MeshType::Trim, lod, 0), scenario);
+                self.clipmap.trims.push(trim);
+                let seam = rs.instance_create2(mesh(MeshType::Seam, lod, 0), scenario);
+                self.clipmap.seams.push(seam);
+            }
+        }
+
+        let visible = self.base().is_visible_in_tree();
+        for instance in self.clipmap.all() {
+            rs.instance_set_visible(instance, visible);
+        }
+
+        self.apply_material();
+        self.update_material();
+        self.update_aabbs();
+        // Force a snap on the next frame
+        self.target_last_position = Vector2::new(f32::MAX, f32::MAX);
+    }
+
+    fn clear_meshes(&mut self) {
+        let mut rs = RenderingServer::singleton();
+        for instance in self.clipmap.all() {
+            rs.free_rid(instance);
+        }
+        self.clipmap = ClipmapInstances::new();
+
+        for mesh in self.meshes.drain(..).flatten() {
+            rs.free_rid(mesh);
+        }
+    }
+
+    fn snap(&mut self, target_position: Vector3) {
+        let mut rs = RenderingServer::singleton();
+        let spacing = self.vertex_spacing;
+        let size = self.mesh_size;
+        let position = Vector3::new(target_position.x, 0.0, target_position.z);
+
+        let mut transform = Transform3D::IDENTITY.scaled(Vector3::new(spacing, 1.0, spacing));
+        transform.origin = (position / spacing).floor() * spacing;
+        rs.instance_set_transform(self.clipmap.cross, transform);
+
+        let mut tile = 0;
+        let mut edge = 0;
+        for lod in 0..self.mesh_lods {
+            let scale = (1 << lod) as f32 * spacing;
+            let snapped_position = (position / scale).floor() * scale;
+            let tile_size = Vector3::new((size << lod) as f32, 0.0, (size << lod) as f32) * spacing;
+            let base = snapped_position
+                - Vector3::new((size << (lod + 1)) as f32, 0.0, (size << (lod + 1)) as f32) * spacing;
+
+            // Position tiles
+            for x in 0..4 {
+                for y in 0..4 {
+                    if lod != 0 && (x == 1 || x == 2) && (y == 1 || y == 2) {
+                        continue;
+                    }
+
+                    let fill = Vector3::new(
+                        if x >= 2 { 1.0 } else { 0.0 },
+                        0.0,
+                        if y >= 2 { 1.0 } else { 0.0 },
+                    ) * scale;
+                    let mut transform = Transform3D::IDENTITY.scaled(Vector3::new(scale, 1.0, scale));
+                    transform.origin = base + Vector3::new(x as f32, 0.0, y as f32) * tile_size + fill;
+                    rs.instance_set_transform(self.clipmap.tiles[tile], transform);
+                    tile += 1;
+                }
+            }
+
+            // Position filler
+            let mut transform = Transform3D::IDENTITY.scaled(Vector3::new(scale, 1.0, scale));
+            transform.origin = snapped_position;
+            rs.instance_set_transform(self.clipmap.fillers[lod as usize], transform);
+
+            if lod != self.mesh_lods - 1 {
+                let next_scale = scale * 2.0;
+                let next_snapped_position = (position / next_scale).floor() * next_scale;
+
+                // Position trim, rotated to the side the next level leaves uncovered
+                let tile_center = snapped_position + Vector3::new(scale, 0.0, scale) * 0.5;
+                let d = position - next_snapped_position;
+                let mut r = 0;
+                r |= if d.x >= scale { 0 } else { 2 };
+                r |= if d.z >= scale { 0 } else { 1 };
+                let rotations: [f32; 4] = [0.0, 270.0, 90.0, 180.0];
+                let angle = rotations[r].to_radians();
+                let mut transform = Transform3D::IDENTITY
+                    .rotated(Vector3::UP, -angle)
+                    .scaled(Vector3::new(scale, 1.0, scale));
+                transform.origin = tile_center;
+                rs.instance_set_transform(self.clipmap.trims[edge], transform);
+
+                // Position seam
+                let next_base = next_snapped_position
+                    - Vector3::new((size << (lod + 1)) as f32, 0.0, (size << (lod + 1)) as f32) * spacing;
+                let mut transform = Transform3D::IDENTITY.scaled(Vector3::new(scale, 1.0, scale));
+                transform.origin = next_base;
+                rs.instance_set_transform(self.clipmap.seams[edge], transform);
+                edge += 1;
+            }
+        }
+    }
+}
+
+impl Drop for FastTerrain {
+    fn drop(&mut self) {
+        if !self.meshes.is_empty() {
+            self.clear_meshes();
+        }
+        self.collision.destroy();
+        if let Some(instancer) = &mut self.instancer {
+            instancer.bind_mut().destroy();
+        }
     }
 }
\ No newline at end of file
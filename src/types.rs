@@ -1,5 +1,3 @@
-use godot::prelude::*;
-
 #[derive(Hash, Eq, PartialEq)]
 pub struct Vector3Hash {
     x: i32,
@@ -8,11 +6,11 @@ pub struct Vector3Hash {
 }
 
 impl Vector3Hash {
-    pub fn from_vector3(v: Vector3) -> Self {
+    pub fn from_array(v: [f32; 3]) -> Self {
         Self {
-            x: (v.x * 100000.0) as i32,
-            y: (v.y * 100000.0) as i32,
-            z: (v.z * 100000.0) as i32,
+            x: (v[0] * 100000.0) as i32,
+            y: (v[1] * 100000.0) as i32,
+            z: (v[2] * 100000.0) as i32,
         }
     }
 }